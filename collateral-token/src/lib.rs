@@ -1,9 +1,11 @@
+use std::collections::HashMap;
+
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider,
 };
 use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::u128;
+use near_sdk::json_types::U128;
 use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
 
 #[near_bindgen]
@@ -16,6 +18,7 @@ pub struct Contract {
     icon: Option<String>,
     max_mint: Option<u128>,
     minter: Option<AccountId>,
+    minter_allowances: HashMap<AccountId, u128>,
 }
 
 near_contract_standards::impl_fungible_token_core!(Contract, token);
@@ -40,6 +43,7 @@ impl Contract {
             icon,
             max_mint,
             minter,
+            minter_allowances: HashMap::new(),
         }
     }
 
@@ -68,6 +72,30 @@ impl Contract {
         self.minter = minter;
     }
 
+    pub fn get_minter(&self) -> Option<AccountId> {
+        self.minter.clone()
+    }
+
+    /// Typed counterpart of `ft_total_supply` for use inside the contract
+    pub fn total_supply(&self) -> U128 {
+        U128(self.token.total_supply)
+    }
+
+    /// Bounds how much `account_id` may still mint, `None` removes the limit
+    pub fn set_minter_allowance(&mut self, account_id: AccountId, allowance: Option<u128>) {
+        if !self.is_owner() {
+            env::panic_str("Owner only");
+        }
+        match allowance {
+            Some(allowance) => self.minter_allowances.insert(account_id, allowance),
+            None => self.minter_allowances.remove(&account_id),
+        };
+    }
+
+    pub fn minter_allowance(&self, account_id: AccountId) -> Option<U128> {
+        self.minter_allowances.get(&account_id).map(|allowance| U128(*allowance))
+    }
+
     /// Naming this ft_* allows the NEAR wallet to discover this token for you
     #[payable]
     pub fn ft_mint(&mut self, receiver_id: AccountId, amount: u128) {
@@ -78,7 +106,15 @@ impl Contract {
             }
         }
         if self.is_owner_or_minter() {
-            self.token.internal_register_account(&receiver_id);
+            if !self.is_owner() {
+                if let Some(allowance) = self.minter_allowances.get_mut(&env::predecessor_account_id()) {
+                    assert!(amount <= *allowance, "Mint allowance exceeded");
+                    *allowance -= amount;
+                }
+            }
+            if !self.token.accounts.contains_key(&receiver_id) {
+                self.token.internal_register_account(&receiver_id);
+            }
             self.token.internal_deposit(&receiver_id, amount.into());
         } else {
             env::panic_str("admin or minter only!");
@@ -94,6 +130,15 @@ impl Contract {
         }
     }
 
+    /// Registers an account again after `unregister_account`, panics if it is still registered
+    pub fn register_account(&mut self, account_id: AccountId) {
+        if self.is_owner_or_minter() {
+            self.token.internal_register_account(&account_id);
+        } else {
+            env::panic_str("admin or minter only!");
+        }
+    }
+
     pub fn unregister_account(&mut self, account_id: &AccountId) {
         if self.is_owner_or_minter() {
             if self.token.accounts.remove(account_id).is_none() {
@@ -105,10 +150,12 @@ impl Contract {
     }
 
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: u128, memo: Option<String>) {
-        self.token.ft_transfer(receiver_id, amount, memo)
+        self.token.ft_transfer(receiver_id, amount.into(), memo)
     }
 
-    fn ft_transfer_from(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: u128, memo: Option<String>) {
+    /// Lets the minter move collateral out of a holder's account, e.g. to a treasury on withdraw
+    #[payable]
+    pub fn ft_transfer_from(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: u128, memo: Option<String>) {
         if self.is_owner_or_minter() {
             self.token.internal_transfer(&sender_id, &receiver_id, amount.into(), memo);
         } else {
@@ -123,8 +170,8 @@ impl Contract {
         amount: u128,
         memo: Option<String>,
         msg: String,
-    ) -> PromiseOrValue<u128> {
-        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    ) -> PromiseOrValue<U128> {
+        self.token.ft_transfer_call(receiver_id, amount.into(), memo, msg)
     }
 }
 
@@ -135,9 +182,15 @@ impl Contract {
         }
     }
 
+    // The predecessor is the immediate caller: when the staking pool mints or burns on a
+    // user's behalf, the signer is that user while the predecessor is the pool.
+    fn is_owner(&self) -> bool {
+        env::predecessor_account_id() == env::current_account_id()
+    }
+
     fn is_owner_or_minter(&self) -> bool {
         if let Some(minter1) = self.minter.clone() {
-            return env::signer_account_id() == env::current_account_id() || env::signer_account_id() == minter1
+            return env::predecessor_account_id() == env::current_account_id() || env::predecessor_account_id() == minter1
         }
         return false;
     }
@@ -0,0 +1,138 @@
+use collateral_token::Contract;
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::{testing_env, AccountId};
+
+fn token_account() -> AccountId {
+    "collateral.near".parse().unwrap()
+}
+
+fn minter() -> AccountId {
+    "pool.near".parse().unwrap()
+}
+
+fn alice() -> AccountId {
+    "alice.near".parse().unwrap()
+}
+
+fn bob() -> AccountId {
+    "bob.near".parse().unwrap()
+}
+
+fn set_context(signer: &AccountId, predecessor: &AccountId) {
+    let context = VMContextBuilder::new()
+        .current_account_id(token_account())
+        .signer_account_id(signer.clone())
+        .predecessor_account_id(predecessor.clone())
+        .attached_deposit(1)
+        .build();
+    testing_env!(context);
+}
+
+fn set_caller(account_id: &AccountId) {
+    set_context(account_id, account_id);
+}
+
+fn new_token() -> Contract {
+    set_caller(&token_account());
+    Contract::new(24, "Collateral".to_string(), "COL".to_string(), None, None, Some(minter()))
+}
+
+#[test]
+fn minter_mints_within_allowance() {
+    let mut contract = new_token();
+    contract.set_minter_allowance(minter(), Some(100));
+
+    set_caller(&minter());
+    contract.ft_mint(alice(), 60);
+    contract.ft_mint(bob(), 40);
+
+    assert_eq!(contract.ft_balance_of(alice()).0, 60);
+    assert_eq!(contract.ft_balance_of(bob()).0, 40);
+    assert_eq!(contract.minter_allowance(minter()).unwrap().0, 0);
+}
+
+#[test]
+#[should_panic(expected = "Mint allowance exceeded")]
+fn minter_cannot_exceed_allowance() {
+    let mut contract = new_token();
+    contract.set_minter_allowance(minter(), Some(100));
+
+    set_caller(&minter());
+    contract.ft_mint(alice(), 60);
+    contract.ft_mint(bob(), 50);
+}
+
+#[test]
+fn owner_mints_without_allowance() {
+    let mut contract = new_token();
+    contract.set_minter_allowance(token_account(), Some(10));
+
+    contract.ft_mint(alice(), 1_000);
+    assert_eq!(contract.ft_balance_of(alice()).0, 1_000);
+}
+
+#[test]
+fn get_minter_returns_configured_minter() {
+    let mut contract = new_token();
+    assert_eq!(contract.get_minter(), Some(minter()));
+
+    contract.set_minter(None);
+    assert_eq!(contract.get_minter(), None);
+}
+
+#[test]
+fn pool_mints_and_burns_on_behalf_of_a_user() {
+    let mut contract = new_token();
+
+    // the user signed the transaction, the pool contract made the call
+    set_context(&alice(), &minter());
+    contract.ft_mint(alice(), 100);
+    contract.ft_burn(alice(), 40);
+
+    assert_eq!(contract.ft_balance_of(alice()).0, 60);
+}
+
+#[test]
+fn total_supply_tracks_mints_and_burns() {
+    let mut contract = new_token();
+    assert_eq!(contract.total_supply().0, 0);
+
+    contract.ft_mint(alice(), 100);
+    contract.ft_mint(bob(), 50);
+    contract.ft_burn(alice(), 30);
+    assert_eq!(contract.total_supply().0, 120);
+    assert_eq!(contract.total_supply(), contract.ft_total_supply());
+}
+
+#[test]
+fn repeated_mints_to_an_account_accumulate() {
+    let mut contract = new_token();
+    contract.ft_mint(alice(), 100);
+    contract.ft_mint(alice(), 50);
+    assert_eq!(contract.ft_balance_of(alice()).0, 150);
+}
+
+#[test]
+fn mint_registers_a_previously_unregistered_account() {
+    let mut contract = new_token();
+    contract.ft_mint(alice(), 100);
+    contract.ft_burn(alice(), 100);
+    contract.unregister_account(&alice());
+
+    contract.ft_mint(alice(), 70);
+    assert_eq!(contract.ft_balance_of(alice()).0, 70);
+}
+
+#[test]
+fn minter_registers_an_unregistered_account() {
+    let mut contract = new_token();
+    contract.ft_mint(bob(), 10);
+    contract.ft_burn(bob(), 10);
+    contract.unregister_account(&bob());
+
+    set_caller(&minter());
+    contract.register_account(bob());
+    contract.ft_mint(bob(), 25);
+    assert_eq!(contract.ft_balance_of(bob()).0, 25);
+}
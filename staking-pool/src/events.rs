@@ -0,0 +1,123 @@
+use crate::*;
+use near_sdk::serde_json::{json, Value};
+
+pub const EVENT_STANDARD: &str = "staking-pool";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+/// Logs a NEP-297 style event: `EVENT_JSON:{"standard", "version", "event", "data"}`
+pub(crate) fn log_event(event: &str, data: Value) {
+    let payload = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", payload));
+}
+
+pub(crate) fn log_reward_shortfall(account_id: &AccountId, pid: u128, index: usize, requested: u128, paid: u128) {
+    log_event(
+        "RewardShortfall",
+        json!({
+            "account_id": account_id,
+            "pid": U128(pid),
+            "index": index,
+            "requested": U128(requested),
+            "paid": U128(paid),
+        }),
+    );
+}
+
+pub(crate) fn log_circuit_breaker_tripped(pid: u128, outflow: u128, limit: u128) {
+    log_event(
+        "CircuitBreakerTripped",
+        json!({
+            "pid": U128(pid),
+            "outflow": U128(outflow),
+            "limit": U128(limit),
+        }),
+    );
+}
+
+/// Deposits reached capacity and the pool stopped taking new ones
+pub(crate) fn log_pool_filled(pid: u128, balance: u128) {
+    log_event(
+        "PoolFilled",
+        json!({
+            "pid": U128(pid),
+            "balance": U128(balance),
+        }),
+    );
+}
+
+pub(crate) fn log_collateral_mismatch(account_id: &AccountId, pid: u128, staked: u128, collateral: u128) {
+    log_event(
+        "CollateralMismatch",
+        json!({
+            "account_id": account_id,
+            "pid": U128(pid),
+            "staked": U128(staked),
+            "collateral": U128(collateral),
+        }),
+    );
+}
+
+pub(crate) fn log_token_recovery(token: &AccountId, receiver_id: &AccountId, amount: u128, success: bool) {
+    log_event(
+        "TokenRecovery",
+        json!({
+            "token": token,
+            "receiver_id": receiver_id,
+            "amount": U128(amount),
+            "success": success,
+        }),
+    );
+}
+
+/// Token metadata reported decimals outside 1..=24, the pool kept `kept`
+pub(crate) fn log_implausible_decimals(pid: u128, token: &AccountId, reported: u8, kept: u8) {
+    log_event(
+        "ImplausibleDecimals",
+        json!({
+            "pid": U128(pid),
+            "token": token,
+            "reported": reported,
+            "kept": kept,
+        }),
+    );
+}
+
+/// Warns that claims on the pool will fall short until rewards are sent with `reward:<pid>`
+pub(crate) fn log_reward_reserve_unfunded(pid: u128) {
+    log_event(
+        "RewardReserveUnfunded",
+        json!({
+            "pid": U128(pid),
+        }),
+    );
+}
+
+pub(crate) fn log_admin_adjustment(account_id: &AccountId, pid: u128, index: usize, old_amount: u128, new_amount: u128) {
+    log_event(
+        "AdminAdjustment",
+        json!({
+            "account_id": account_id,
+            "pid": U128(pid),
+            "index": index,
+            "old_amount": U128(old_amount),
+            "new_amount": U128(new_amount),
+        }),
+    );
+}
+
+/// Audit trail of owner calls, `pid` is omitted for contract-wide actions
+pub(crate) fn log_admin_action(method: &str, pid: Option<u128>) {
+    log_event(
+        "AdminAction",
+        json!({
+            "method": method,
+            "pid": pid.map(U128),
+            "caller": env::signer_account_id(),
+        }),
+    );
+}
@@ -9,17 +9,41 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, near_bindgen, AccountId, Balance, PromiseOrValue,
+    env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseError, PromiseOrValue,
 };
 use near_sdk::{Gas, PanicOnDefault};
 
+mod events;
+mod migration;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub const ONE_HOUR: u128 = 3600_000;
 pub const ONE_DAY: u128 = 86400_000;
 pub const QUARTER_DAY: u64 = 86400_000 * 90;
 
+pub const MAX_BATCH_QUERY: usize = 100;
+/// Upper bound on `pool_info.len()` so views iterating every pool stay within gas
+pub const MAX_POOLS: usize = 100;
+/// Most decimals accepted from token metadata, NEAR itself uses 24
+pub const MAX_TOKEN_DECIMALS: u8 = 24;
+/// Scale of `reward_per_token_stored`, the accumulator keeps this many fractional units per token
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
 pub const FT_TRANSFER_GAS: Gas = Gas(10_000_000_000_000);
+pub const CALLBACK_GAS: Gas = Gas(20_000_000_000_000);
 pub const DEPOSIT_ONE_YOCTO: Balance = 1;
 
+uint::construct_uint! {
+    /// Wide intermediate for products of 24-decimal amounts
+    pub struct U256(4);
+}
+
+/// `a * b / c` without overflowing on the intermediate product
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    return (U256::from(a) * U256::from(b) / U256::from(c)).as_u128();
+}
+
 #[ext_contract(ext_ft)]
 trait FungibleToken {
     // change methods
@@ -41,11 +65,13 @@ trait FungibleToken {
 
     fn ft_mint(&mut self, receiver_id: AccountId, amount: u128);
     fn ft_burn(&mut self, account_id: AccountId, amount: u128);
+    fn ft_transfer_from(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: u128, memo: Option<String>);
 
     // view methods
     fn ft_total_supply(&self) -> String;
     fn ft_balance_of(&self, account_id: String) -> String;
     fn ft_metadata(&self) -> FungibleTokenMetadata;
+    fn get_minter(&self) -> Option<AccountId>;
 }
 
 #[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -57,6 +83,26 @@ pub enum PoolType {
     Loan,
 }
 
+/// How stakers' rewards are sized
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RewardMode {
+    /// `apy` on each position
+    Apy,
+    /// `total_reward` spread evenly over `period` ms from `end_time`, shared pro rata by stake
+    FixedBudget { total_reward: u128, period: u64 },
+}
+
+/// How a loan pool's borrow rate follows utilisation
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum InterestModel {
+    /// `apy` scaled by utilisation
+    Linear,
+    /// `base_bps` rising by `slope1_bps` up to `kink_bps` utilisation, then by `slope2_bps` up to full utilisation
+    Kinked { kink_bps: u128, base_bps: u128, slope1_bps: u128, slope2_bps: u128 },
+}
+
 #[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub enum TransactionType {
@@ -71,8 +117,16 @@ pub enum TransactionType {
 pub struct UserInfo {
     transaction_type: TransactionType, // tx type
     amount: u128,       // amount of tx
-    time: u64,         // start
+    time: u64,         // last update
+    accrual_start: u64,     // reward/interest accrual anchor
+    paused_offset: u64,     // pool's paused accrual time when accrual_start was set
     paid_out: u128,
+    unpaid_reward: u128,    // settled reward not paid out yet
+    reward_debt: u128,      // amount * reward_per_token_stored / REWARD_PRECISION at the last settlement
+    withdrawn: u128,    // principal withdrawn so far
+    twab: u128,         // amount integrated over time (amount * ms) up to `twab_updated_at`
+    twab_updated_at: u64,
+    last_claimed_at: u64,   // last time the position's reward was paid, 0 before the first payout
 }
 
 #[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone)]
@@ -81,6 +135,9 @@ pub struct TokenInfo {
     token: AccountId, // tx type
     collateral_token: AccountId,       // amount of tx
     decimals: u8,         // start
+    reward_decimals: Option<u8>,  // owner override of the metadata decimals for reward amounts
+    collateral_decimals: Option<u8>,  // decimals of the collateral token, same as `decimals` if unset
+    accepted_collateral: Vec<(AccountId, u128)>,   // (token, collateral_factor_bps) borrowers of a loan pool may post, empty for unsecured loans
     name: String,
     symbol: String,
 }
@@ -94,6 +151,9 @@ pub struct DepositLimiters {
     limit_per_user: u128,   // limit per user
     capacity: u128,         // pool capacity
     max_utilisation: u128,  // maximum utilisation of pool
+    min_reserve_bps: u128,  // share of the balance that must stay unborrowed
+    withdraw_buffer_bps: u128,  // share of each deposit withdrawable whatever the utilisation, kept free from borrows
+    stake_cooldown_ms: u64,     // minimum time between a user's deposits
 }
 
 #[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone)]
@@ -101,20 +161,61 @@ pub struct DepositLimiters {
 pub struct Funds {
     balance: u128,         // pool balance
     loaned_balance: u128,   // loaned amount on loan pool
+    reward_reserve: u128,   // tokens set aside to pay rewards
+    total_rewards_paid: u128,   // cumulative rewards paid out
+    fees_collected: u128,   // withdrawal fees kept by the pool
+    tvl_time: u128,     // balance integrated over time (balance * ms) up to `reward_updated_at`
 }
 
 #[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PoolInfo {
     pool_name: String,         // pool name
+    icon: Option<String>,      // pool logo, data url or link
+    description: Option<String>,   // pool description for frontends
     pool_type: PoolType,       // pool type
     apy: u128,         // apy of pool
+    reward_mode: RewardMode,   // apy or a fixed budget shared by stakers
+    boost: Option<(u64, u64, u128)>,  // (start, end, bonus_bps) added on top of the apy in that window
+    min_interest: u128,    // minimum interest charged on a loan repayment
+    max_interest_bps: u128,    // cap on a loan's interest relative to its principal, 0 disables
+    withdraw_fee_bps: u128,    // fee on withdrawn principal, in basis points
+    origination_fee_bps: u128,     // fee kept from a new loan's payout, the full amount is still owed
+    interest_model: InterestModel,     // borrow rate curve of loan pools
+    claim_cooldown_ms: u64,   // minimum time between a user's reward claims
+    claim_burn: Option<(AccountId, u128)>,  // token and amount burnt from the claimer before each reward claim is paid
+    collateral_burn: bool,    // burn collateral on withdraw, otherwise send it to collateral_sink
+    collateral_sink: Option<AccountId>,   // receives withdrawn collateral when not burning
+    outflow_limit: u128,      // withdrawals per window that pause the pool, 0 disables
+    outflow_window_ms: u64,   // length of the outflow window
+    outflow_window_start: u64,    // start of the current outflow window
+    outflow_volume: u128,     // withdrawn in the current outflow window
     paused: bool,         // pause flag
+    deposits_closed: bool,    // balance reached capacity, reopens once withdrawals make room
+    gated: bool,          // staking restricted to whitelisted accounts
+    rewards_paused: bool,     // reward accrual pause flag
+    rewards_paused_at: u64,   // start of the current reward pause
+    rewards_paused_duration: u64,     // accrual time lost to finished reward pauses
+    reward_per_token_stored: u128,    // staking reward accrued per token, scaled by REWARD_PRECISION
+    reward_updated_at: u64,   // last time reward_per_token_stored was brought up to date
     quarterly_payout: bool,   // if true, claim quarterly
+    mint_rewards: bool,       // mint rewards on the pool token instead of paying from reward_reserve, the contract must be its minter
     unique_users: u128,         // stakers and borrowers
+    active_borrowers: u128,     // users with an outstanding loan
     token_info: TokenInfo,  // token info of pool
     funds: Funds,       // balance status of pool
     deposit_limiters: DepositLimiters,       // deposit limiter of pool
+    archived: bool,       // removed pool kept as a tombstone
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DebugUserState {
+    pub is_pool_user: bool,
+    pub is_whitelisted: bool,
+    pub total_staked: U128,
+    pub total_borrowed: U128,
+    pub positions: usize,
 }
 
 #[near_bindgen]
@@ -126,6 +227,18 @@ pub struct Contract {
     user_info: HashMap<u128, HashMap<AccountId, Vec<UserInfo>>>,    // user's tx array in pid
     total_user_amount_staked: HashMap<u128, HashMap<AccountId, u128>>,  // user's stake amount in pid
     total_user_amount_borrowed: HashMap<u128, HashMap<AccountId, u128>>,    // user's borrowed amount in pid
+    max_borrow: HashMap<u128, HashMap<AccountId, u128>>,    // user's outstanding borrow limit in pid
+    last_claim_time: HashMap<u128, HashMap<AccountId, u64>>,    // user's last reward claim in pid
+    last_stake_time: HashMap<u128, HashMap<AccountId, u64>>,    // user's last deposit in pid
+    operators: HashMap<AccountId, HashMap<AccountId, bool>>,    // operators approved by an account
+    borrow_operators: HashMap<AccountId, HashMap<AccountId, bool>>,   // operators an account lets borrow in its name
+    processed_nonces: HashMap<AccountId, HashMap<u64, bool>>,   // deposit nonces already credited per sender
+    version: String,    // schema version of the stored state
+    unique_pool_names: bool,    // reject pools reusing an existing name
+    distinct_collateral: bool,  // staking pools must use a collateral token other than the staked one
+    guardian: Option<AccountId>,    // may pause pools but has no other admin rights
+    solvency_snapshot: HashMap<AccountId, (u128, u128)>,  // (held, owed) per pool token at the last refresh
+    posted_collateral: HashMap<u128, HashMap<AccountId, HashMap<AccountId, u128>>>,   // borrower's posted amount per collateral token in pid
 }
 
 // init
@@ -140,90 +253,407 @@ impl Contract {
             user_info: HashMap::new(),
             total_user_amount_staked: HashMap::new(),
             total_user_amount_borrowed: HashMap::new(),
+            max_borrow: HashMap::new(),
+            last_claim_time: HashMap::new(),
+            last_stake_time: HashMap::new(),
+            operators: HashMap::new(),
+            borrow_operators: HashMap::new(),
+            processed_nonces: HashMap::new(),
+            version: VERSION.to_string(),
+            unique_pool_names: false,
+            distinct_collateral: true,
+            guardian: None,
+            solvency_snapshot: HashMap::new(),
+            posted_collateral: HashMap::new(),
         }
     }
+
+    /// Converts state stored in the first deployment's layout after an upgrade, stamped
+    /// with the deployed version
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: migration::OldContract = env::state_read().expect("failed to read state");
+        old.into_current()
+    }
 }
 
 // admin
 #[near_bindgen]
 impl Contract {
-    pub fn set_pool_paused(&mut self, pid: u128, flag: bool) {
+    pub fn set_guardian(&mut self, guardian: Option<AccountId>) {
         self.assert_caller_allowed();
+        self.guardian = guardian;
+        events::log_admin_action("set_guardian", None);
+    }
+
+    /// The guardian may pause pools without the owner key, unpausing stays with the owner
+    pub fn set_pool_paused(&mut self, pid: u128, flag: bool) {
+        self.assert_owner_or_guardian();
+        assert!(flag || self.is_owner(), "only the owner can unpause");
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         pool.paused = flag;
+        events::log_admin_action("set_pool_paused", Some(pid));
+    }
+
+    /// Freezes reward and interest accrual without blocking deposits or withdrawals
+    pub fn set_rewards_paused(&mut self, pid: u128, flag: bool) {
+        self.assert_caller_allowed();
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+
+        if pool.rewards_paused == flag {
+            return;
+        }
+        Self::_update_reward_per_token(pool);
+        if flag {
+            pool.rewards_paused_at = env::block_timestamp_ms();
+        } else {
+            pool.rewards_paused_duration = Self::_paused_reward_time(pool);
+        }
+        pool.rewards_paused = flag;
     }
 
     pub fn whitelist(&mut self, pid: u128, user: AccountId, status: bool) {
         self.assert_caller_allowed();
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
-        assert!(pool.pool_type == PoolType::Loan, "no loans from here");
-        let is_whitelisted = self.is_whitelisted.get_mut(&pid).unwrap().get_mut(&user).unwrap();
+        assert!(pool.pool_type == PoolType::Loan || pool.gated, "no loans from here");
+        let is_whitelisted = self.is_whitelisted.entry(pid).or_default().entry(user).or_default();
         *is_whitelisted = status;
+        events::log_admin_action("whitelist", Some(pid));
+    }
+
+    /// Whitelists `user` and sets the most they may have borrowed at once, in one call
+    pub fn configure_borrower(&mut self, pid: u128, user: AccountId, whitelisted: bool, max_borrow: u128) {
+        self.whitelist(pid, user.clone(), whitelisted);
+        let user_max_borrow = self.max_borrow.entry(pid).or_default().entry(user).or_default();
+        *user_max_borrow = max_borrow;
+    }
+
+    pub fn set_unique_pool_names(&mut self, flag: bool) {
+        self.assert_caller_allowed();
+        self.unique_pool_names = flag;
+    }
+
+    pub fn set_distinct_collateral(&mut self, flag: bool) {
+        self.assert_caller_allowed();
+        self.distinct_collateral = flag;
     }
 
     pub fn create_pool(&mut self, pool_info: PoolInfo, pool_type: PoolType) {
         self.assert_caller_allowed();
         let mut t_pool_info = pool_info.clone();
 
+        assert!(self.pool_info.len() < MAX_POOLS, "too many pools");
+        if self.unique_pool_names {
+            assert!(self.pool_info.iter().all(|pool| pool.pool_name != pool_info.pool_name), "pool name already exists");
+        }
+        assert!(pool_info.withdraw_fee_bps <= 10_000, "withdraw fee above 100%");
+        assert!(pool_info.origination_fee_bps <= 10_000, "origination fee above 100%");
+        assert!(pool_info.claim_burn.as_ref().map_or(true, |(_, amount)| *amount > 0), "claim burn amount is zero");
+        assert!(pool_info.deposit_limiters.withdraw_buffer_bps <= 10_000, "withdraw buffer above 100%");
+        if let RewardMode::FixedBudget { period, .. } = pool_info.reward_mode {
+            assert!(pool_type == PoolType::Staking && period > 0, "fixed budget needs a staking pool and a period");
+        }
+        assert!(pool_info.token_info.accepted_collateral.iter().all(|(_, factor_bps)| *factor_bps <= 10_000), "collateral factor above 100%");
+        if let InterestModel::Kinked { kink_bps, .. } = pool_info.interest_model {
+            assert!(kink_bps > 0 && kink_bps < 10_000, "kink must be within (0, 10000) bps");
+        }
+        assert!(pool_info.collateral_burn || pool_info.collateral_sink.is_some(), "collateral sink required");
+
         if pool_type != PoolType::Loan {
             assert!(pool_info.deposit_limiters.start_time < pool_info.deposit_limiters.end_time, "end time should be after start time");
+            assert!(pool_info.deposit_limiters.duration > 0, "duration should be greater than zero");
+            assert!(pool_info.deposit_limiters.end_time > env::block_timestamp_ms(), "end time already passed");
+            if self.distinct_collateral {
+                assert!(pool_info.token_info.collateral_token != pool_info.token_info.token, "collateral token same as staked token");
+            }
         }
 
         t_pool_info.funds.balance = 0;
         t_pool_info.funds.loaned_balance = 0;
+        t_pool_info.funds.reward_reserve = 0;
+        t_pool_info.funds.total_rewards_paid = 0;
+        t_pool_info.funds.fees_collected = 0;
+        t_pool_info.funds.tvl_time = 0;
         t_pool_info.unique_users = 0;
-
+        t_pool_info.active_borrowers = 0;
+        t_pool_info.archived = false;
+        t_pool_info.deposits_closed = false;
+        t_pool_info.rewards_paused = false;
+        t_pool_info.rewards_paused_at = 0;
+        t_pool_info.rewards_paused_duration = 0;
+        t_pool_info.reward_per_token_stored = 0;
+        t_pool_info.reward_updated_at = 0;
+        t_pool_info.outflow_window_start = 0;
+        t_pool_info.outflow_volume = 0;
+
+        // rewards are paid from a reserve kept apart from principal, which starts out empty
+        let pays_rewards = match t_pool_info.reward_mode {
+            RewardMode::Apy => t_pool_info.apy > 0,
+            RewardMode::FixedBudget { total_reward, .. } => total_reward > 0,
+        };
+        let needs_reserve = !t_pool_info.mint_rewards && pays_rewards;
         self.pool_info.push(t_pool_info);
+        let pid = u128::try_from(self.pool_info.len() - 1).unwrap();
+        events::log_admin_action("create_pool", Some(pid));
+        if needs_reserve {
+            events::log_reward_reserve_unfunded(pid);
+        }
+        self.verify_collateral_minter(pid);
+    }
+
+    /// Copies the staked token's decimals, name and symbol onto the stored pool. Views can't
+    /// make cross-contract calls, so this is how pool metadata gets refreshed.
+    pub fn refresh_token_metadata(&mut self, pid: u128) -> Promise {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        ext_ft::ext(pool.token_info.token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_metadata()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .ft_metadata_callback(pid)
+            )
+    }
+
+    /// Checks with the collateral token that this contract is allowed to mint it,
+    /// otherwise every deposit would fail on the mint
+    pub fn verify_collateral_minter(&self, pid: u128) -> Promise {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        ext_ft::ext(pool.token_info.collateral_token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .get_minter()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .verify_collateral_minter_callback(pid)
+            )
     }
 
     pub fn edit_pool(&mut self, pid: u128, new_pool_info: PoolInfo) {
         self.assert_caller_allowed();
         let mut t_new_pool_info = new_pool_info.clone();
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        assert!(!pool.archived, "pool archived");
+        // rewards up to now accrue at the old rate
+        Self::_update_reward_per_token(pool);
+        assert!(new_pool_info.withdraw_fee_bps <= 10_000, "withdraw fee above 100%");
+        assert!(new_pool_info.origination_fee_bps <= 10_000, "origination fee above 100%");
+        assert!(new_pool_info.claim_burn.as_ref().map_or(true, |(_, amount)| *amount > 0), "claim burn amount is zero");
+        assert!(new_pool_info.deposit_limiters.withdraw_buffer_bps <= 10_000, "withdraw buffer above 100%");
+        if let RewardMode::FixedBudget { period, .. } = new_pool_info.reward_mode {
+            assert!(new_pool_info.pool_type == PoolType::Staking && period > 0, "fixed budget needs a staking pool and a period");
+        }
+        assert!(new_pool_info.token_info.accepted_collateral.iter().all(|(_, factor_bps)| *factor_bps <= 10_000), "collateral factor above 100%");
+        if let InterestModel::Kinked { kink_bps, .. } = new_pool_info.interest_model {
+            assert!(kink_bps > 0 && kink_bps < 10_000, "kink must be within (0, 10000) bps");
+        }
+        assert!(new_pool_info.collateral_burn || new_pool_info.collateral_sink.is_some(), "collateral sink required");
 
         t_new_pool_info.funds.balance = pool.funds.balance;
         t_new_pool_info.funds.loaned_balance = pool.funds.loaned_balance;
+        t_new_pool_info.funds.reward_reserve = pool.funds.reward_reserve;
+        t_new_pool_info.funds.total_rewards_paid = pool.funds.total_rewards_paid;
+        t_new_pool_info.funds.fees_collected = pool.funds.fees_collected;
+        t_new_pool_info.funds.tvl_time = pool.funds.tvl_time;
         t_new_pool_info.unique_users = pool.unique_users;
+        t_new_pool_info.active_borrowers = pool.active_borrowers;
         t_new_pool_info.token_info.token = pool.token_info.token.clone();
+        t_new_pool_info.archived = pool.archived;
+        t_new_pool_info.deposits_closed = pool.deposits_closed;
+        t_new_pool_info.rewards_paused = pool.rewards_paused;
+        t_new_pool_info.rewards_paused_at = pool.rewards_paused_at;
+        t_new_pool_info.rewards_paused_duration = pool.rewards_paused_duration;
+        t_new_pool_info.reward_per_token_stored = pool.reward_per_token_stored;
+        t_new_pool_info.reward_updated_at = pool.reward_updated_at;
+        t_new_pool_info.outflow_window_start = pool.outflow_window_start;
+        t_new_pool_info.outflow_volume = pool.outflow_volume;
 
         *pool = t_new_pool_info;
+        Self::_sync_deposits_closed(pid, pool);
+        events::log_admin_action("edit_pool", Some(pid));
     }
 
-    pub fn recover_token(&mut self, token: AccountId, amount: u128) {
+    pub fn set_pool_metadata(&mut self, pid: u128, icon: Option<String>, description: Option<String>) {
         self.assert_caller_allowed();
-        ext_ft::ext(token)
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        pool.icon = icon;
+        pool.description = description;
+        events::log_admin_action("set_pool_metadata", Some(pid));
+    }
+
+    /// Overrides the token metadata decimals used for reward amounts, e.g. when the metadata is wrong
+    pub fn set_reward_decimals(&mut self, pid: u128, decimals: u8) {
+        self.assert_caller_allowed();
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        pool.token_info.reward_decimals = Some(decimals);
+        events::log_admin_action("set_reward_decimals", Some(pid));
+    }
+
+    /// Sets a staking position's recorded amount, e.g. after an off-chain reconciliation. The pool
+    /// balance and the user's total follow, collateral is left as it is.
+    pub fn admin_adjust_stake(&mut self, pid: u128, user: AccountId, index: usize, new_amount: u128) {
+        self.assert_caller_allowed();
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.entry(pid).or_default().entry(user.clone()).or_default();
+
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+        let old_amount = transaction[index].amount;
+        assert!(pool.funds.balance + new_amount >= pool.funds.loaned_balance + old_amount, "insufficient pool balance");
+
+        Self::_update_reward_per_token(pool);
+        Self::_settle_pending_reward(pool, &mut transaction[index]);
+        Self::_accrue_twab(&mut transaction[index]);
+        transaction[index].amount = new_amount;
+        Self::_reset_reward_debt(pool, &mut transaction[index]);
+
+        let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(user.clone()).or_default();
+        *total_user_amount_staked = *total_user_amount_staked + new_amount - old_amount;
+        pool.funds.balance = pool.funds.balance + new_amount - old_amount;
+        Self::_sync_deposits_closed(pid, pool);
+
+        events::log_admin_adjustment(&user, pid, index, old_amount, new_amount);
+        events::log_admin_action("admin_adjust_stake", Some(pid));
+        self._delete_stake_if_empty(user, pid, index);
+    }
+
+    /// Archives an empty pool. Pools are never taken out of `pool_info`: every map is keyed
+    /// by pid, so removing an element would renumber the pools after it and detach their state.
+    pub fn remove_pool(&mut self, pid: u128) {
+        self.assert_caller_allowed();
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+
+        assert!(!pool.archived, "pool archived");
+        assert!(pool.unique_users == 0 && pool.funds.balance == 0 && pool.funds.loaned_balance == 0, "pool not empty");
+
+        pool.archived = true;
+        pool.paused = true;
+        // the leftover reserve is no longer owed and becomes recoverable
+        pool.funds.reward_reserve = 0;
+    }
+
+    /// Sends the pool's collected withdrawal and origination fees to `receiver_id`. They are
+    /// booked out up front and restored by `resolve_withdraw_fees` if the transfer fails.
+    pub fn withdraw_fees(&mut self, pid: u128, receiver_id: AccountId) -> Promise {
+        self.assert_caller_allowed();
+        assert!(receiver_id != env::current_account_id(), "fee receiver is the contract itself");
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+
+        let amount = pool.funds.fees_collected;
+        assert!(amount > 0, "no fees collected");
+        pool.funds.fees_collected = 0;
+        events::log_admin_action("withdraw_fees", Some(pid));
+
+        ext_ft::ext(pool.token_info.token.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .with_attached_deposit(DEPOSIT_ONE_YOCTO)
-            .ft_transfer(
-                env::current_account_id().to_string(),
-                amount.to_string(),
-                Some("0".to_string()),
-            );
+            .ft_transfer(receiver_id.to_string(), amount.to_string(), Some("0".to_string()))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .resolve_withdraw_fees(pid, U128(amount))
+            )
+    }
+
+    /// Queries this contract's balance of every pool token for `global_solvency`
+    pub fn refresh_global_solvency(&mut self) {
+        self.assert_caller_allowed();
+
+        let mut tokens: Vec<AccountId> = self.pool_info.iter().map(|pool| pool.token_info.token.clone()).collect();
+        tokens.sort();
+        tokens.dedup();
+        for token in tokens {
+            ext_ft::ext(token.clone())
+                .with_static_gas(FT_TRANSFER_GAS)
+                .ft_balance_of(env::current_account_id().to_string())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_GAS)
+                        .global_solvency_callback(token)
+                );
+        }
+    }
+
+    /// Sends stray tokens to `receiver_id`, resolves to whether they were sent and
+    /// `resolve_recover` logs the outcome. Tokens used by a pool can only be swept above
+    /// what the pools still owe, so the balance is checked first and the amount capped
+    /// in the callback.
+    pub fn recover_token(&mut self, token: AccountId, amount: u128, receiver_id: AccountId) -> Promise {
+        self.assert_caller_allowed();
+        // the owner is the contract account, sending there would leave the tokens where they are
+        assert!(receiver_id != env::current_account_id(), "recovery receiver is the contract itself");
+        events::log_admin_action("recover_token", None);
+
+        if !self._is_pool_token(&token) {
+            return Self::_recover_transfer(token, receiver_id, amount);
+        }
+
+        ext_ft::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_balance_of(env::current_account_id().to_string())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .recover_token_callback(token, receiver_id, U128(amount))
+            )
     }
 }
 
 // external
 #[near_bindgen]
 impl Contract {
-    fn internal_deposit_and_stake(&mut self, staker: AccountId, pid: u128, token_id: AccountId, amount: u128) {
+    /// Stakes what fits under the pool capacity and returns the amount left unused
+    fn internal_deposit_and_stake(&mut self, staker: AccountId, pid: u128, token_id: AccountId, amount: u128) -> u128 {
+        assert!(amount > 0, "amount should be greater than zero");
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(staker.clone()).or_default();
 
-        assert!(!pool.paused, "Pool Paused");
-        assert_eq!(pool.token_info.token, token_id, "invalid token or pool id");
+        assert!(!pool.archived, "pool archived: pid {}", pid);
+        // a filled pool refunds late depositors instead of rejecting them
+        if pool.deposits_closed {
+            return amount;
+        }
+        assert!(!pool.paused, "Pool Paused: pid {}", pid);
+        assert_eq!(pool.token_info.token, token_id, "invalid token or pool id: pid {} takes {}", pid, pool.token_info.token);
 
         if pool.pool_type == PoolType::Staking {
-            assert!(env::block_timestamp_ms() >= pool.deposit_limiters.start_time && env::block_timestamp_ms() <= pool.deposit_limiters.end_time, "deposits disabled at this time");
+            assert!(
+                env::block_timestamp_ms() >= pool.deposit_limiters.start_time && env::block_timestamp_ms() <= pool.deposit_limiters.end_time,
+                "deposits disabled at this time: pid {} takes deposits from {} to {}",
+                pid, pool.deposit_limiters.start_time, pool.deposit_limiters.end_time
+            );
+        }
+        assert!(amount <= pool.deposit_limiters.limit_per_user, "amount exceeds limit per transaction: pid {} takes at most {}", pid, pool.deposit_limiters.limit_per_user);
+        if let Some(last_stake_time) = self.last_stake_time.get(&pid).and_then(|times| times.get(&staker)) {
+            let next_stake_time = last_stake_time + pool.deposit_limiters.stake_cooldown_ms;
+            assert!(env::block_timestamp_ms() >= next_stake_time, "stake cooldown active: pid {} takes the next deposit at {}", pid, next_stake_time);
         }
-        assert!(amount <= pool.deposit_limiters.limit_per_user, "amount exceeds limit per transaction");
-        assert!(pool.funds.balance + amount <= pool.deposit_limiters.capacity, "pool capacity reached");
 
-        let user_info = UserInfo {
+        let capacity_left = pool.deposit_limiters.capacity.saturating_sub(pool.funds.balance);
+        let unused = amount.saturating_sub(capacity_left);
+        let amount = amount - unused;
+        if amount == 0 {
+            return unused;
+        }
+        self.last_stake_time.entry(pid).or_default().insert(staker.clone(), env::block_timestamp_ms());
+
+        Self::_update_reward_per_token(pool);
+        let mut user_info = UserInfo {
             transaction_type: TransactionType::Staking,
             amount,
             time: env::block_timestamp_ms(),
-            paid_out: 0
+            accrual_start: env::block_timestamp_ms(),
+            paused_offset: Self::_paused_reward_time(pool),
+            paid_out: 0,
+            unpaid_reward: 0,
+            reward_debt: 0,
+            withdrawn: 0,
+            twab: 0,
+            twab_updated_at: env::block_timestamp_ms(),
+            last_claimed_at: 0,
         };
+        Self::_reset_reward_debt(pool, &mut user_info);
         transaction.push(user_info);
 
         ext_ft::ext(pool.token_info.collateral_token.clone())
@@ -231,55 +661,107 @@ impl Contract {
             .with_attached_deposit(DEPOSIT_ONE_YOCTO)
             .ft_mint(
                 staker.clone(),
-                amount
+                Self::_collateral_amount(&pool.token_info, amount)
             );
 
         let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(staker.clone()).or_default();
         *total_user_amount_staked = *total_user_amount_staked + amount;
 
         pool.funds.balance += amount;
+        Self::_sync_deposits_closed(pid, pool);
         
         let is_pool_user = self.is_pool_user.entry(pid).or_default().entry(staker.clone()).or_default();
         if *is_pool_user == false {
             pool.unique_users += 1;
         }
         *is_pool_user = true;
+
+        return unused;
     }
     
     pub fn emergency_withdraw(&mut self, pid: u128, index: usize, amount: u128) {
         let account_id = env::signer_account_id();
+        self.internal_emergency_withdraw(account_id, pid, index, amount);
+    }
+
+    fn internal_emergency_withdraw(&mut self, account_id: AccountId, pid: u128, index: usize, amount: u128) {
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
 
-        ext_ft::ext(pool.token_info.collateral_token.clone())
-            .with_static_gas(FT_TRANSFER_GAS)
-            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
-            .ft_burn(
-                account_id.clone(),
-                amount
-            );
+        assert!(!pool.paused, "Pool Paused");
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+        assert!(amount <= transaction[index].amount, "amount greater than transaction");
+        assert!(pool.funds.balance >= pool.funds.loaned_balance + amount, "insufficient pool balance");
+
+        Self::_update_reward_per_token(pool);
+        Self::_settle_pending_reward(pool, &mut transaction[index]);
+        Self::_release_collateral(pool, &account_id, amount);
 
+        let fee = amount * pool.withdraw_fee_bps / 10_000;
         ext_ft::ext(pool.token_info.token.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .with_attached_deposit(DEPOSIT_ONE_YOCTO)
             .ft_transfer(
                 account_id.clone().to_string(),
-                amount.to_string(),
+                (amount - fee).to_string(),
                 Some("0".to_string()),
             );
+        pool.funds.fees_collected += fee;
 
+        Self::_accrue_twab(&mut transaction[index]);
         transaction[index].amount -= amount;
+        transaction[index].withdrawn += amount;
         transaction[index].time = env::block_timestamp_ms();
+        transaction[index].accrual_start = env::block_timestamp_ms();
+        transaction[index].paused_offset = Self::_paused_reward_time(pool);
+        Self::_reset_reward_debt(pool, &mut transaction[index]);
+
+        let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(account_id.clone()).or_default();
+        *total_user_amount_staked = *total_user_amount_staked - amount;
+
+        pool.funds.balance -= amount;
+        Self::_sync_deposits_closed(pid, pool);
+        Self::_record_outflow(pid, pool, amount);
+
+        self._delete_stake_if_empty(account_id, pid, index);
     }
 
     pub fn withdraw(&mut self, pid: u128, index: usize, amount: u128) {
         let account_id = env::signer_account_id();
-        
+        self.internal_withdraw(account_id, pid, index, amount);
+    }
+
+    /// Lets `operator` withdraw or stop withdrawing on behalf of the caller
+    pub fn set_operator(&mut self, operator: AccountId, approved: bool) {
+        let account_id = env::signer_account_id();
+        let is_operator = self.operators.entry(account_id).or_default().entry(operator).or_default();
+        *is_operator = approved;
+    }
+
+    /// Lets `operator` borrow or stop borrowing on behalf of the caller, separate from `set_operator`
+    /// since a borrow opens debt in the caller's name
+    pub fn set_borrow_operator(&mut self, operator: AccountId, approved: bool) {
+        let account_id = env::signer_account_id();
+        let is_operator = self.borrow_operators.entry(account_id).or_default().entry(operator).or_default();
+        *is_operator = approved;
+    }
+
+    /// Withdraws `owner`'s position, proceeds still go to `owner`. The operator is the
+    /// predecessor so managing contracts can call it for their users.
+    pub fn withdraw_for(&mut self, owner: AccountId, pid: u128, index: usize, amount: u128) {
+        let operator = env::predecessor_account_id();
+        assert!(self.is_operator(owner.clone(), operator), "not an approved operator");
+        self.internal_withdraw(owner, pid, index, amount);
+    }
+
+    fn internal_withdraw(&mut self, account_id: AccountId, pid: u128, index: usize, amount: u128) {
         let temp_pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().clone();
         let temp_transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap().clone();
+        assert!(!temp_pool.paused, "Pool Paused: pid {}", pid);
         
         if env::block_timestamp_ms() < temp_pool.deposit_limiters.end_time {
-            self.emergency_withdraw(pid, index, amount);
+            self.internal_emergency_withdraw(account_id, pid, index, amount);
             return ;
         }
 
@@ -287,52 +769,241 @@ impl Contract {
         assert!(amount <= temp_transaction[index].amount, "amount greater than transaction");
 
         if temp_pool.pool_type == PoolType::Staking {
-            assert!(env::block_timestamp_ms() >=  temp_pool.deposit_limiters.end_time + temp_pool.deposit_limiters.duration, "withdrawing too early");
+            let unlock_time = temp_pool.deposit_limiters.end_time + temp_pool.deposit_limiters.duration;
+            assert!(env::block_timestamp_ms() >= unlock_time, "withdrawing too early: pid {} unlocks at {}", pid, unlock_time);
+            // staking pools never lend today, this keeps a pool that does from paying out lent funds
+            assert!(
+                temp_pool.funds.balance >= temp_pool.funds.loaned_balance + amount,
+                "high utilisation: pid {} has {} of {} lent out",
+                pid, temp_pool.funds.loaned_balance, temp_pool.funds.balance
+            );
         } else {
-            assert!(temp_pool.funds.balance >= temp_pool.funds.loaned_balance + amount, "high utilisation");
+            assert!(
+                temp_pool.funds.balance >= temp_pool.funds.loaned_balance + amount,
+                "high utilisation: pid {} has {} of {} lent out",
+                pid, temp_pool.funds.loaned_balance, temp_pool.funds.balance
+            );
             let projected_utilisation = self._calculate_percentage(
                 temp_pool.funds.loaned_balance,
                 temp_pool.funds.balance - amount
             );
-            assert!(projected_utilisation < temp_pool.deposit_limiters.max_utilisation, "utilisation maxed out");
+            // borrows leave the buffered share free, so it can always be taken out
+            let buffered = temp_transaction[index].amount * temp_pool.deposit_limiters.withdraw_buffer_bps / 10_000;
+            assert!(
+                amount <= buffered || projected_utilisation < temp_pool.deposit_limiters.max_utilisation,
+                "utilisation maxed out: pid {} would be at {}%, max {}%",
+                pid, projected_utilisation, temp_pool.deposit_limiters.max_utilisation
+            );
         }
 
-        self.transfer_rewards(account_id.clone(), pid, index, env::block_timestamp_ms() - temp_pool.deposit_limiters.end_time, amount);
+        let mut rewards = self._book_rewards(account_id.clone(), pid, index, env::block_timestamp_ms() - temp_pool.deposit_limiters.end_time, amount);
         
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
 
-        ext_ft::ext(pool.token_info.collateral_token.clone())
-            .with_static_gas(FT_TRANSFER_GAS)
-            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
-            .ft_burn(
-                account_id.clone(),
-                amount
-            );
+        // minted rewards don't come out of the pool's holdings, only the principal is at stake below
+        if pool.mint_rewards && rewards > 0 {
+            ext_ft::ext(pool.token_info.token.clone())
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+                .ft_mint(account_id.clone(), rewards);
+            rewards = 0;
+        }
+
+        Self::_release_collateral(pool, &account_id, amount);
 
+        // principal and reward leave in one transfer, so neither can be paid without the other
+        let fee = amount * pool.withdraw_fee_bps / 10_000;
         ext_ft::ext(pool.token_info.token.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .with_attached_deposit(DEPOSIT_ONE_YOCTO)
             .ft_transfer(
                 account_id.clone().to_string(),
-                amount.to_string(),
+                (amount - fee + rewards).to_string(),
                 Some("0".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .withdraw_callback(account_id.clone(), pid, U128(amount), U128(fee), U128(rewards))
             );
+        pool.funds.fees_collected += fee;
 
+        Self::_accrue_twab(&mut transaction[index]);
         transaction[index].amount -= amount;
+        transaction[index].withdrawn += amount;
         transaction[index].time = env::block_timestamp_ms();
+        transaction[index].accrual_start = env::block_timestamp_ms();
+        transaction[index].paused_offset = Self::_paused_reward_time(pool);
+        Self::_reset_reward_debt(pool, &mut transaction[index]);
 
         let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(account_id.clone()).or_default();
         *total_user_amount_staked = *total_user_amount_staked - amount;
 
         pool.funds.balance -= amount;
+        Self::_sync_deposits_closed(pid, pool);
+        Self::_record_outflow(pid, pool, amount);
 
         self._delete_stake_if_empty(account_id, pid, index);
     }
 
+    /// Restakes the position's claimable reward from the reserve and mints matching collateral,
+    /// so the collateral balance keeps tracking the principal. Returns the compounded amount.
+    /// Same pause and cooldown rules as `claim_quarterly_payout`.
+    pub fn compound_rewards(&mut self, pid: u128, index: usize) -> U128 {
+        let account_id = env::signer_account_id();
+        let temp_transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap();
+
+        assert!(index < temp_transaction.len(), "invalid position index");
+        assert!(temp_transaction[index].transaction_type == TransactionType::Staking, "not staked");
+
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        assert!(!pool.paused, "Pool Paused");
+        assert!(!pool.rewards_paused, "Rewards Paused");
+
+        let last_claim_time = self.last_claim_time.entry(pid).or_default().entry(account_id.clone()).or_default();
+        if *last_claim_time > 0 {
+            assert!(env::block_timestamp_ms() >= *last_claim_time + pool.claim_cooldown_ms, "claim cooldown active");
+        }
+        *last_claim_time = env::block_timestamp_ms();
+
+        let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
+
+        Self::_update_reward_per_token(pool);
+        Self::_settle_pending_reward(pool, &mut transaction[index]);
+        let compounded = std::cmp::min(transaction[index].unpaid_reward, pool.funds.reward_reserve);
+        assert!(compounded > 0, "nothing to compound");
+
+        pool.funds.reward_reserve -= compounded;
+        pool.funds.total_rewards_paid += compounded;
+        pool.funds.balance += compounded;
+        Self::_sync_deposits_closed(pid, pool);
+        let collateral_token = pool.token_info.collateral_token.clone();
+        let collateral_amount = Self::_collateral_amount(&pool.token_info, compounded);
+
+        // the grown principal only earns from now on
+        transaction[index].unpaid_reward -= compounded;
+        transaction[index].paid_out += compounded;
+        Self::_accrue_twab(&mut transaction[index]);
+        transaction[index].amount += compounded;
+        Self::_reset_reward_debt(pool, &mut transaction[index]);
+
+        let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(account_id.clone()).or_default();
+        *total_user_amount_staked = *total_user_amount_staked + compounded;
+
+        ext_ft::ext(collateral_token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_mint(
+                account_id,
+                collateral_amount
+            );
+
+        return U128(compounded);
+    }
+
+    /// Moves a staking position to `to`. The caller's last position takes over `index`
+    /// (same swap-pop as `_delete_stake_if_empty`) and the moved one is appended to `to`'s list.
+    /// The caller's collateral is burnt first, `resolve_transfer_position` mints it to `to` or
+    /// moves the position back if the burn failed.
+    pub fn transfer_position(&mut self, pid: u128, index: usize, to: AccountId) {
+        let account_id = env::signer_account_id();
+        assert_ne!(account_id, to, "cannot transfer to self");
+        assert!(self._can_stake(pid, &to), "receiver not whitelisted: pid {} is gated", pid);
+
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        assert!(!pool.archived, "pool archived: pid {}", pid);
+        assert!(!pool.paused, "Pool Paused: pid {}", pid);
+        let transaction = self.user_info.get(&pid).and_then(|users| users.get(&account_id)).cloned().unwrap_or_default();
+
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+        let position = &transaction[index];
+        assert!(position.amount <= pool.deposit_limiters.limit_per_user, "amount exceeds limit per transaction: pid {} takes at most {}", pid, pool.deposit_limiters.limit_per_user);
+
+        // collateral follows the position
+        let collateral_token = pool.token_info.collateral_token.clone();
+        let collateral_amount = Self::_collateral_amount(&pool.token_info, position.amount);
+        let (amount, accrual_start) = (position.amount, position.accrual_start);
+        self._move_position(pid, &account_id, &to, index);
+
+        ext_ft::ext(collateral_token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_burn(account_id.clone(), collateral_amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .resolve_transfer_position(pid, account_id, to, U128(amount), accrual_start)
+            );
+    }
+
+    /// Carves `amount` off a staking position into a new position appended to the caller's list.
+    /// Both keep the original anchors, rewards already paid or owed are split pro rata.
+    pub fn split_position(&mut self, pid: u128, index: usize, amount: u128) {
+        let account_id = env::signer_account_id();
+        let transaction = self.user_info.entry(pid).or_default().entry(account_id).or_default();
+
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+        assert!(amount > 0 && amount < transaction[index].amount, "split amount out of range");
+
+        let original = &mut transaction[index];
+        Self::_accrue_twab(original);
+        let mut split = original.clone();
+        split.amount = amount;
+        split.twab = original.twab * amount / original.amount;
+        split.paid_out = original.paid_out * amount / original.amount;
+        split.unpaid_reward = original.unpaid_reward * amount / original.amount;
+        split.reward_debt = original.reward_debt * amount / original.amount;
+        split.withdrawn = 0;
+
+        original.amount -= split.amount;
+        original.paid_out -= split.paid_out;
+        original.unpaid_reward -= split.unpaid_reward;
+        original.reward_debt -= split.reward_debt;
+        original.twab -= split.twab;
+        transaction.push(split);
+    }
+
+    /// Reads `user`'s collateral balance from the collateral token and checks it against
+    /// their recorded stake, see `collateral_balance_callback`
+    pub fn collateral_balance(&self, pid: u128, user: AccountId) -> Promise {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        ext_ft::ext(pool.token_info.collateral_token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_balance_of(user.to_string())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .collateral_balance_callback(pid, user)
+            )
+    }
+
     pub fn borrow(&mut self, pid: u128, amount: u128) {
         let account_id = env::signer_account_id();
+        self.internal_borrow(account_id, pid, amount);
+    }
+
+    /// Borrows on `borrower`'s behalf, the debt and the tokens both go to `borrower`. The operator
+    /// is the predecessor, approved by the borrower through `set_borrow_operator`.
+    pub fn borrow_for(&mut self, borrower: AccountId, pid: u128, amount: u128) {
+        let operator = env::predecessor_account_id();
+        assert!(self.is_borrow_operator(borrower.clone(), operator), "not an approved borrow operator");
+        self.internal_borrow(borrower, pid, amount);
+    }
+
+    fn internal_borrow(&mut self, account_id: AccountId, pid: u128, amount: u128) {
+        assert!(amount > 0, "amount should be greater than zero");
         assert_eq!(self.is_whitelisted.get(&pid).unwrap().get(&account_id).unwrap().clone(), true, "Only whitelisted can borrow");
+        if let Some(max_borrow) = self.max_borrow.get(&pid).and_then(|limits| limits.get(&account_id)) {
+            let borrowed = self.total_user_amount_borrowed.get(&pid).and_then(|borrowed| borrowed.get(&account_id)).cloned().unwrap_or(0);
+            assert!(borrowed + amount <= *max_borrow, "borrow limit exceeded");
+        }
+        if !self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().token_info.accepted_collateral.is_empty() {
+            let borrowed = self.total_user_amount_borrowed.get(&pid).and_then(|borrowed| borrowed.get(&account_id)).cloned().unwrap_or(0);
+            assert!(borrowed + amount <= self.borrow_capacity(pid, account_id.clone()).0, "insufficient collateral");
+        }
         
         let temp_pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().clone();
         let projected_utilisation = self._calculate_percentage(
@@ -343,29 +1014,66 @@ impl Contract {
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let loans = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
 
-        assert!(pool.pool_type == PoolType::Loan, "no loans from here");
-        assert!(!pool.paused, "Pool Paused");
-        assert!(pool.funds.balance > 0, "Nothing deposited");
-        assert!(projected_utilisation < pool.deposit_limiters.max_utilisation, "utilisation maxed out");
+        assert!(pool.pool_type == PoolType::Loan, "no loans from here: pid {} is not a loan pool", pid);
+        assert!(!pool.archived, "pool archived: pid {}", pid);
+        assert!(!pool.paused, "Pool Paused: pid {}", pid);
+        assert!(pool.funds.balance > 0, "Nothing deposited: pid {}", pid);
+        assert!(
+            projected_utilisation < pool.deposit_limiters.max_utilisation,
+            "utilisation maxed out: pid {} would be at {}%, max {}%",
+            pid, projected_utilisation, pool.deposit_limiters.max_utilisation
+        );
+        let free_reserve = pool.funds.balance.saturating_sub(pool.funds.loaned_balance + amount);
+        let reserve_bps = free_reserve * 10000 / pool.funds.balance;
+        assert!(
+            reserve_bps >= pool.deposit_limiters.min_reserve_bps,
+            "reserve below minimum: pid {} would keep {} bps, min {} bps",
+            pid, reserve_bps, pool.deposit_limiters.min_reserve_bps
+        );
+        assert!(
+            reserve_bps >= pool.deposit_limiters.withdraw_buffer_bps,
+            "withdraw buffer reserved: pid {} would keep {} bps, buffer {} bps",
+            pid, reserve_bps, pool.deposit_limiters.withdraw_buffer_bps
+        );
 
+        // the borrower receives the amount net of the fee but owes all of it
+        let fee = amount * pool.origination_fee_bps / 10_000;
         ext_ft::ext(pool.token_info.token.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .with_attached_deposit(DEPOSIT_ONE_YOCTO)
             .ft_transfer(
                 account_id.clone().to_string(),
-                amount.to_string(),
+                (amount - fee).to_string(),
                 Some("0".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .resolve_borrow(account_id.clone(), pid, U128(amount), U128(fee), env::block_timestamp_ms())
             );
+        pool.funds.fees_collected += fee;
 
+        Self::_update_reward_per_token(pool);
         let user_info = UserInfo {
             transaction_type: TransactionType::Borrow,
             amount,
             time: env::block_timestamp_ms(),
-            paid_out: 0
+            accrual_start: env::block_timestamp_ms(),
+            paused_offset: Self::_paused_reward_time(pool),
+            paid_out: 0,
+            unpaid_reward: 0,
+            reward_debt: 0,
+            withdrawn: 0,
+            twab: 0,
+            twab_updated_at: env::block_timestamp_ms(),
+            last_claimed_at: 0,
         };
         loans.push(user_info);
 
         let total_user_amount_borrowed = self.total_user_amount_borrowed.entry(pid).or_default().entry(account_id.clone()).or_default();
+        if *total_user_amount_borrowed == 0 {
+            pool.active_borrowers += 1;
+        }
         *total_user_amount_borrowed = * total_user_amount_borrowed + amount;
 
         pool.funds.loaned_balance += amount;
@@ -377,7 +1085,54 @@ impl Contract {
         *is_pool_user = true;
     }
 
-    fn internal_repay(&mut self, borrower: AccountId, pid: u128, index: usize, token_id: AccountId, amount: u128, repay_amount: u128) {
+    /// Repays a loan without `ft_transfer_call`: pulls `max_amount` from the caller through
+    /// `ft_transfer_from`, which the caller must have approved. Interest keeps accruing until the
+    /// callback settles the loan, so `max_amount` should leave a margin, the overage is refunded.
+    pub fn repay(&mut self, pid: u128, index: usize, repay_amount: u128, max_amount: u128) -> Promise {
+        let account_id = env::signer_account_id();
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap();
+
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Borrow, "not borrwed");
+        assert!(repay_amount <= transaction[index].amount, "repay amount greater than borrowed");
+
+        let owed = repay_amount + self.calculate_interest(account_id.clone(), pid, index, repay_amount);
+        assert!(max_amount >= owed, "max amount less than repay amount + interest");
+        ext_ft::ext(pool.token_info.token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_transfer_from(account_id.clone(), env::current_account_id(), max_amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .repay_callback(account_id, pid, index, U128(max_amount), U128(repay_amount))
+            )
+    }
+
+    /// Splits one deposit across pools of the same token: pulls the total from the caller through
+    /// `ft_transfer_from` and stakes each `(pid, amount)`. If any pool refuses, all of it is refunded.
+    pub fn deposit_split(&mut self, allocations: Vec<(u128, u128)>) -> Promise {
+        let account_id = env::signer_account_id();
+        assert!(!allocations.is_empty(), "nothing to deposit");
+        let token = self.pool_info.get(usize::try_from(allocations[0].0).unwrap()).unwrap().token_info.token.clone();
+
+        assert!(self._can_take_split(&account_id, &token, &allocations), "split deposit rejected: a pool appears twice, refuses the deposit or lacks capacity");
+
+        let total: u128 = allocations.iter().map(|(_, amount)| amount).sum();
+        ext_ft::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_transfer_from(account_id.clone(), env::current_account_id(), total, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .deposit_split_callback(account_id, token, allocations)
+            )
+    }
+
+    /// Returns the part of `amount` above the repaid principal and interest, refunded by `ft_on_transfer`
+    fn internal_repay(&mut self, borrower: AccountId, pid: u128, index: usize, token_id: AccountId, amount: u128, repay_amount: u128) -> u128 {
         let interest = self.calculate_interest(borrower.clone(), pid, index, repay_amount);
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(borrower.clone()).or_default();
@@ -388,22 +1143,75 @@ impl Contract {
         assert!(repay_amount <= transaction[index].amount, "repay amount greater than borrowed");
         assert!(amount >= repay_amount + interest, "amount less than repay amount + interest");
 
+        Self::_update_reward_per_token(pool);
+
+        // interest was only charged on the repaid part, so the rest keeps accruing from the same anchor
+        Self::_accrue_twab(&mut transaction[index]);
         transaction[index].amount -= repay_amount;
         transaction[index].time = env::block_timestamp_ms();
 
         let total_user_amount_borrowed = self.total_user_amount_borrowed.entry(pid).or_default().entry(borrower.clone()).or_default();
-        *total_user_amount_borrowed = * total_user_amount_borrowed - amount;
+        *total_user_amount_borrowed = * total_user_amount_borrowed - repay_amount;
+        if *total_user_amount_borrowed == 0 {
+            pool.active_borrowers -= 1;
+        }
 
-        pool.funds.loaned_balance -= amount;
+        pool.funds.loaned_balance -= repay_amount;
+        pool.funds.reward_reserve += interest;
 
         self._delete_stake_if_empty(borrower, pid, index);
+        // interest keeps growing until the transfer lands, so borrowers can send a margin and get the rest back
+        return amount - repay_amount - interest;
+    }
+
+    fn internal_post_collateral(&mut self, borrower: AccountId, pid: u128, token_id: AccountId, amount: u128) {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        assert!(pool.pool_type == PoolType::Loan, "no loans from here");
+        assert!(pool.token_info.accepted_collateral.iter().any(|(token, _)| *token == token_id), "collateral not accepted");
+
+        let posted = self.posted_collateral.entry(pid).or_default().entry(borrower).or_default().entry(token_id).or_default();
+        *posted += amount;
+    }
+
+    /// Returns posted collateral as long as what is left still covers the caller's outstanding principal
+    pub fn withdraw_collateral(&mut self, pid: u128, token: AccountId, amount: u128) {
+        let account_id = env::signer_account_id();
+        let posted = self.posted_collateral.entry(pid).or_default().entry(account_id.clone()).or_default().entry(token.clone()).or_default();
+        assert!(amount <= *posted, "amount greater than posted collateral");
+        *posted -= amount;
+
+        let borrowed = self.total_user_amount_borrowed.get(&pid).and_then(|borrowed| borrowed.get(&account_id)).cloned().unwrap_or(0);
+        assert!(borrowed <= self.borrow_capacity(pid, account_id.clone()).0, "insufficient collateral");
+
+        ext_ft::ext(token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_transfer(
+                account_id.to_string(),
+                amount.to_string(),
+                Some("0".to_string()),
+            );
+    }
+
+    fn internal_fund_rewards(&mut self, pid: u128, token_id: AccountId, amount: u128) {
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        assert_eq!(pool.token_info.token, token_id, "invalid token or pool id");
+
+        pool.funds.reward_reserve += amount;
     }
 
+    /// Pays the position's claimable reward. Rejected while the pool or its rewards are
+    /// paused, so an incident freeze also stops reward outflow. With a `claim_burn` the
+    /// reward is paid once the burn succeeded.
     pub fn claim_quarterly_payout(&mut self, pid: u128, index: usize) {
         let account_id = env::signer_account_id();
         let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().clone();
         let transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap().clone();
 
+        assert!(!pool.paused, "Pool Paused");
+        assert!(!pool.rewards_paused, "Rewards Paused");
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
         assert!(pool.quarterly_payout, "quarterlyPayout disabled for pool");
         assert!(pool.pool_type == PoolType::Staking, "poolType not Staking");
         assert!(env::block_timestamp_ms() > pool.deposit_limiters.end_time, "not started");
@@ -415,9 +1223,50 @@ impl Contract {
 
         let quarters_passed = time_diff / QUARTER_DAY;
         assert!(quarters_passed > 0, "too early");
-        
+
+        let last_claim_time = self.last_claim_time.entry(pid).or_default().entry(account_id.clone()).or_default();
+        if *last_claim_time > 0 {
+            assert!(env::block_timestamp_ms() >= *last_claim_time + pool.claim_cooldown_ms, "claim cooldown active");
+        }
+        *last_claim_time = env::block_timestamp_ms();
+
+        if let Some((burn_token, burn_amount)) = pool.claim_burn {
+            Self::_burn_for_claim(account_id, pid, Some(index), time_diff, burn_token, burn_amount);
+            return;
+        }
         self.transfer_rewards(account_id, pid, index, time_diff, transaction[index].amount);
     }
+
+    /// Claims the reward of every matured staking position of the caller and returns the total
+    /// paid. Rewards mature when the pool unlocks, or a quarter after `end_time` for quarterly
+    /// payout pools. Same pause and cooldown rules as `claim_quarterly_payout`. With a
+    /// `claim_burn` the rewards are paid once the burn succeeded and 0 is returned here.
+    pub fn claim_all_rewards(&mut self, pid: u128) -> U128 {
+        let account_id = env::signer_account_id();
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().clone();
+
+        assert!(!pool.paused, "Pool Paused");
+        assert!(!pool.rewards_paused, "Rewards Paused");
+
+        let now = env::block_timestamp_ms();
+        let quarter_passed = pool.quarterly_payout && pool.pool_type == PoolType::Staking
+            && now >= pool.deposit_limiters.end_time + QUARTER_DAY;
+        if !quarter_passed && now < Self::_unlock_time(&pool) {
+            return U128(0);
+        }
+
+        let last_claim_time = self.last_claim_time.entry(pid).or_default().entry(account_id.clone()).or_default();
+        if *last_claim_time > 0 {
+            assert!(now >= *last_claim_time + pool.claim_cooldown_ms, "claim cooldown active");
+        }
+        *last_claim_time = now;
+
+        if let Some((burn_token, burn_amount)) = pool.claim_burn {
+            Self::_burn_for_claim(account_id, pid, None, 0, burn_token, burn_amount);
+            return U128(0);
+        }
+        return U128(self._claim_positions(account_id, pid));
+    }
 }
 
 // private and internal
@@ -427,7 +1276,8 @@ impl Contract {
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
 
-        if transaction[index].amount == 0 {
+        // a position still owed a reward shortfall stays until that is paid
+        if transaction[index].amount == 0 && transaction[index].unpaid_reward == 0 {
             transaction[index] = transaction[transaction.len() - 1].clone();
             transaction.pop();
         }
@@ -438,17 +1288,397 @@ impl Contract {
         }
     }
 
-    fn _calculate_percentage(&self, value: u128, of: u128) -> u128 {
-        if of == 0 {
-            return 0;
-        } else {
-            let percentage = value * 100 / of;
-            return percentage;
+    /// Swap-removes position `index` of `from` and appends it to `to`, with the stake totals
+    /// and pool user counts following it
+    fn _move_position(&mut self, pid: u128, from: &AccountId, to: &AccountId, index: usize) {
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.entry(pid).or_default().entry(from.clone()).or_default();
+
+        let position = transaction.swap_remove(index);
+        let amount = position.amount;
+        if transaction.len() == 0 {
+            let is_pool_user = self.is_pool_user.entry(pid).or_default().entry(from.clone()).or_default();
+            *is_pool_user = false;
+            pool.unique_users -= 1;
+        }
+        self.user_info.entry(pid).or_default().entry(to.clone()).or_default().push(position);
+
+        let sender_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(from.clone()).or_default();
+        *sender_amount_staked = *sender_amount_staked - amount;
+        let receiver_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(to.clone()).or_default();
+        *receiver_amount_staked = *receiver_amount_staked + amount;
+
+        let is_pool_user = self.is_pool_user.entry(pid).or_default().entry(to.clone()).or_default();
+        if *is_pool_user == false {
+            pool.unique_users += 1;
+        }
+        *is_pool_user = true;
+    }
+
+    /// False for accounts missing from the whitelist of a gated staking pool
+    fn _can_stake(&self, pid: u128, account_id: &AccountId) -> bool {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        if !pool.gated {
+            return true;
+        }
+        return self.is_whitelisted.get(&pid).and_then(|users| users.get(account_id)).cloned().unwrap_or(false);
+    }
+
+    /// Whether `internal_deposit_and_stake` would take `amount` of `token` into the pool, without panicking
+    fn _can_deposit(&self, pid: u128, account_id: &AccountId, token: &AccountId, amount: u128) -> bool {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let now = env::block_timestamp_ms();
+        let window_open = pool.pool_type != PoolType::Staking
+            || (now >= pool.deposit_limiters.start_time && now <= pool.deposit_limiters.end_time);
+
+        return amount > 0
+            && !pool.archived
+            && !pool.paused
+            && !pool.deposits_closed
+            && pool.token_info.token == *token
+            && window_open
+            && amount <= pool.deposit_limiters.limit_per_user
+            && !self._in_stake_cooldown(pid, account_id)
+            && self._can_stake(pid, account_id);
+    }
+
+    /// Room left under the pool's capacity
+    fn _capacity_left(&self, pid: u128) -> u128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        return pool.deposit_limiters.capacity.saturating_sub(pool.funds.balance);
+    }
+
+    /// Whether every leg of a split deposit can be credited in full, each pool at most once:
+    /// the first leg starts the stake cooldown, so a second one into the same pool would fail
+    fn _can_take_split(&self, staker: &AccountId, token: &AccountId, allocations: &[(u128, u128)]) -> bool {
+        return allocations.iter().enumerate().all(|(i, (pid, amount))| {
+            !allocations[..i].iter().any(|(other, _)| other == pid)
+                && self._can_deposit(*pid, staker, token, *amount)
+                && *amount <= self._capacity_left(*pid)
+        });
+    }
+
+    /// True while the account's last deposit into the pool is less than `stake_cooldown_ms` ago
+    fn _in_stake_cooldown(&self, pid: u128, account_id: &AccountId) -> bool {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        return match self.last_stake_time.get(&pid).and_then(|times| times.get(account_id)) {
+            Some(last_stake_time) => env::block_timestamp_ms() < last_stake_time + pool.deposit_limiters.stake_cooldown_ms,
+            None => false,
+        };
+    }
+
+    fn _is_pool_token(&self, token: &AccountId) -> bool {
+        self.pool_info.iter().any(|pool| {
+            pool.token_info.token == *token
+                || pool.token_info.collateral_token == *token
+                || pool.token_info.accepted_collateral.iter().any(|(accepted, _)| accepted == token)
+        })
+    }
+
+    /// Amount of `token` the contract owes to stakers, reward claims and borrowers' posted collateral across all pools.
+    fn _tracked_token_funds(&self, token: &AccountId) -> u128 {
+        let pool_funds: u128 = self.pool_info.iter()
+            .filter(|pool| pool.token_info.token == *token)
+            .map(|pool| pool.funds.balance - pool.funds.loaned_balance + pool.funds.reward_reserve + pool.funds.fees_collected)
+            .sum();
+        let posted: u128 = self.posted_collateral.values()
+            .flat_map(|borrowers| borrowers.values())
+            .filter_map(|posted| posted.get(token))
+            .sum();
+        return pool_funds + posted;
+    }
+
+    fn _recover_transfer(token: AccountId, receiver_id: AccountId, amount: u128) -> Promise {
+        ext_ft::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_transfer(
+                receiver_id.to_string(),
+                amount.to_string(),
+                Some("0".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .resolve_recover(token, receiver_id, U128(amount))
+            )
+    }
+
+    /// Collateral matching `amount` of the pool token, scaled by the decimals difference and rounded down
+    fn _collateral_amount(token_info: &TokenInfo, amount: u128) -> u128 {
+        let collateral_decimals = token_info.collateral_decimals.unwrap_or(token_info.decimals);
+        if collateral_decimals >= token_info.decimals {
+            return amount * 10u128.pow(u32::from(collateral_decimals - token_info.decimals));
+        }
+        return amount / 10u128.pow(u32::from(token_info.decimals - collateral_decimals));
+    }
+
+    /// Burns the collateral backing withdrawn principal, or moves it to the pool's sink
+    fn _release_collateral(pool: &PoolInfo, account_id: &AccountId, amount: u128) {
+        let amount = Self::_collateral_amount(&pool.token_info, amount);
+        let collateral = ext_ft::ext(pool.token_info.collateral_token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO);
+
+        if pool.collateral_burn {
+            collateral.ft_burn(account_id.clone(), amount);
+        } else {
+            collateral.ft_transfer_from(account_id.clone(), pool.collateral_sink.clone().unwrap(), amount, None);
+        }
+    }
+
+    /// Undoes `_release_collateral` after a failed withdraw
+    fn _restore_collateral(pool: &PoolInfo, account_id: &AccountId, amount: u128) {
+        let amount = Self::_collateral_amount(&pool.token_info, amount);
+        let collateral = ext_ft::ext(pool.token_info.collateral_token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO);
+
+        if pool.collateral_burn {
+            collateral.ft_mint(account_id.clone(), amount);
+        } else {
+            collateral.ft_transfer_from(pool.collateral_sink.clone().unwrap(), account_id.clone(), amount, None);
+        }
+    }
+
+    /// Closes deposits once the balance reaches capacity and reopens them when it drops below.
+    /// Only deposits are affected, withdrawals, claims and loans carry on.
+    fn _sync_deposits_closed(pid: u128, pool: &mut PoolInfo) {
+        let full = pool.funds.balance >= pool.deposit_limiters.capacity;
+        if full && !pool.deposits_closed {
+            events::log_pool_filled(pid, pool.funds.balance);
+        }
+        pool.deposits_closed = full;
+    }
+
+    /// Adds a withdrawal to the pool's rolling outflow and pauses the pool once the
+    /// volume within `outflow_window_ms` goes above `outflow_limit`.
+    fn _record_outflow(pid: u128, pool: &mut PoolInfo, amount: u128) {
+        if pool.outflow_limit == 0 {
+            return;
+        }
+
+        let now = env::block_timestamp_ms();
+        if now >= pool.outflow_window_start + pool.outflow_window_ms {
+            pool.outflow_window_start = now;
+            pool.outflow_volume = 0;
+        }
+        pool.outflow_volume += amount;
+
+        if pool.outflow_volume > pool.outflow_limit {
+            pool.paused = true;
+            events::log_circuit_breaker_tripped(pid, pool.outflow_volume, pool.outflow_limit);
+        }
+    }
+
+    /// Upper bound of the rewards still owed to a staking position: the full reward window
+    /// for staking pools, accrual so far at full utilisation for loan pools, the position's
+    /// share of the whole budget for fixed budget pools.
+    fn _max_unpaid_reward(&self, pool: &PoolInfo, position: &UserInfo) -> u128 {
+        if let RewardMode::FixedBudget { total_reward, .. } = pool.reward_mode {
+            if pool.funds.balance == 0 {
+                return 0;
+            }
+            let reward = mul_div(total_reward, position.amount, pool.funds.balance);
+            return reward.saturating_sub(position.paid_out);
+        }
+
+        let accrual_time: u128;
+        if pool.pool_type == PoolType::Staking {
+            accrual_time = pool.deposit_limiters.duration as u128;
+        } else {
+            accrual_time = env::block_timestamp_ms().saturating_sub(position.time) as u128;
+        }
+
+        let reward = mul_div(position.amount, pool.apy * accrual_time, 100 * 365 * ONE_DAY);
+        return reward.saturating_sub(position.paid_out);
+    }
+
+    /// Utilisation the staking rewards accrue at: always 100 for staking pools
+    fn _reward_utilisation(pool: &PoolInfo) -> u128 {
+        if pool.pool_type == PoolType::Staking {
+            return 100;
+        }
+        if pool.funds.balance == 0 {
+            return 0;
+        }
+        return std::cmp::min(pool.funds.loaned_balance * 100 / pool.funds.balance, 100);
+    }
+
+    /// Limits loan interest to `max_interest_bps` of the principal
+    fn _cap_interest(pool: &PoolInfo, principal: u128, interest: u128) -> u128 {
+        if pool.max_interest_bps == 0 {
+            return interest;
+        }
+        return std::cmp::min(interest, principal * pool.max_interest_bps / 10_000);
+    }
+
+    /// Yearly rate in basis points at `utilisation` percent. Staking pools are always linear.
+    fn _interest_rate_bps(pool: &PoolInfo, utilisation: u128) -> u128 {
+        match pool.interest_model {
+            InterestModel::Kinked { kink_bps, base_bps, slope1_bps, slope2_bps } if pool.pool_type == PoolType::Loan => {
+                let utilisation_bps = utilisation * 100;
+                if utilisation_bps <= kink_bps {
+                    return base_bps + slope1_bps * utilisation_bps / kink_bps;
+                }
+                return base_bps + slope1_bps + slope2_bps * (utilisation_bps - kink_bps) / (10_000 - kink_bps);
+            }
+            _ => return pool.apy * utilisation,
+        }
+    }
+
+    /// `reward_per_token_stored` plus what accrued since `reward_updated_at`
+    fn _reward_per_token(pool: &PoolInfo) -> u128 {
+        if pool.rewards_paused {
+            return pool.reward_per_token_stored;
+        }
+
+        let from = Self::_clamp_to_accrual_window(pool, pool.reward_updated_at);
+        let to = Self::_clamp_to_accrual_window(pool, env::block_timestamp_ms());
+
+        if let RewardMode::FixedBudget { total_reward, period } = pool.reward_mode {
+            let budget_end = pool.deposit_limiters.end_time + period;
+            let accrual_time = std::cmp::min(to, budget_end).saturating_sub(from) as u128;
+            if pool.funds.balance == 0 {
+                return pool.reward_per_token_stored;
+            }
+            let released = mul_div(total_reward, accrual_time, period as u128);
+            return pool.reward_per_token_stored + mul_div(released, REWARD_PRECISION, pool.funds.balance);
+        }
+
+        let accrual_time = to.saturating_sub(from) as u128;
+        let mut reward_per_token = REWARD_PRECISION * pool.apy * Self::_reward_utilisation(pool) * accrual_time;
+
+        if let Some((boost_start, boost_end, bonus_bps)) = pool.boost {
+            let boosted_time = std::cmp::min(to, boost_end).saturating_sub(std::cmp::max(from, boost_start)) as u128;
+            reward_per_token += REWARD_PRECISION * pool.apy * Self::_reward_utilisation(pool) * boosted_time * bonus_bps / 10_000;
+        }
+        return pool.reward_per_token_stored + reward_per_token / (100 * 100 * 365 * ONE_DAY);
+    }
+
+    /// Brings the accumulator up to date. Must run before anything that changes the
+    /// accrual rate (apy, balances, pause flag) so past time keeps the old rate.
+    fn _update_reward_per_token(pool: &mut PoolInfo) {
+        pool.reward_per_token_stored = Self::_reward_per_token(pool);
+        pool.funds.tvl_time = Self::_tvl_time(pool);
+        pool.reward_updated_at = env::block_timestamp_ms();
+    }
+
+    /// `tvl_time` including the balance held since the last checkpoint
+    fn _tvl_time(pool: &PoolInfo) -> u128 {
+        let elapsed = env::block_timestamp_ms().saturating_sub(pool.reward_updated_at);
+        return pool.funds.tvl_time + pool.funds.balance * elapsed as u128;
+    }
+
+    /// Brings `twab` up to now, must run before the position's amount changes
+    fn _accrue_twab(position: &mut UserInfo) {
+        let elapsed = env::block_timestamp_ms().saturating_sub(position.twab_updated_at);
+        position.twab += position.amount * elapsed as u128;
+        position.twab_updated_at = env::block_timestamp_ms();
+    }
+
+    /// Moves what the position accrued since its last settlement into `unpaid_reward`
+    fn _settle_pending_reward(pool: &PoolInfo, position: &mut UserInfo) {
+        let accrued = mul_div(position.amount, pool.reward_per_token_stored, REWARD_PRECISION);
+        position.unpaid_reward += accrued.saturating_sub(position.reward_debt);
+        position.reward_debt = accrued;
+    }
+
+    /// Starts accruing from the current accumulator, after the principal changed
+    fn _reset_reward_debt(pool: &PoolInfo, position: &mut UserInfo) {
+        position.reward_debt = mul_div(position.amount, pool.reward_per_token_stored, REWARD_PRECISION);
+    }
+
+    /// When principal can be withdrawn: after the reward duration for staking pools,
+    /// after the deposit window for loan pools
+    fn _unlock_time(pool: &PoolInfo) -> u64 {
+        if pool.pool_type == PoolType::Staking {
+            return pool.deposit_limiters.end_time + pool.deposit_limiters.duration;
+        }
+        return pool.deposit_limiters.end_time;
+    }
+
+    /// Clamps `time` into the window where the pool accrues rewards
+    fn _clamp_to_accrual_window(pool: &PoolInfo, time: u64) -> u64 {
+        if pool.pool_type == PoolType::Staking {
+            let end = pool.deposit_limiters.end_time + pool.deposit_limiters.duration;
+            return std::cmp::min(std::cmp::max(time, pool.deposit_limiters.end_time), end);
+        }
+        return time;
+    }
+
+    /// Accrual time lost to reward pauses so far, including a pause still in progress
+    fn _paused_reward_time(pool: &PoolInfo) -> u64 {
+        let mut paused_time = pool.rewards_paused_duration;
+        if pool.rewards_paused {
+            paused_time += Self::_clamp_to_accrual_window(pool, env::block_timestamp_ms())
+                - Self::_clamp_to_accrual_window(pool, pool.rewards_paused_at);
+        }
+        return paused_time;
+    }
+
+    fn _calculate_percentage(&self, value: u128, of: u128) -> u128 {
+        if of == 0 {
+            return 0;
+        } else {
+            let percentage = value * 100 / of;
+            return percentage;
         }
     }
 
     fn transfer_rewards(&mut self, receiver_id: AccountId, pid: u128, index: usize, duration: u64, amount: u128) -> u128 {
-        let reward = self.calculate_interest(receiver_id.clone(), pid, index, amount);
+        let paid_rewards = self._book_rewards(receiver_id.clone(), pid, index, duration, amount);
+        // a withdrawn position kept for its shortfall goes once the rest is paid
+        self._delete_stake_if_empty(receiver_id.clone(), pid, index);
+        if paid_rewards == 0 {
+            return 0;
+        }
+
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let reward_token = ext_ft::ext(pool.token_info.token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO);
+        if pool.mint_rewards {
+            reward_token.ft_mint(receiver_id, paid_rewards);
+        } else {
+            reward_token.ft_transfer(
+                receiver_id.clone().to_string(),
+                paid_rewards.to_string(),
+                Some("0".to_string()),
+            );
+        }
+        return paid_rewards;
+    }
+
+    /// Pays the reward of every staking position of the account, returns the total paid
+    fn _claim_positions(&mut self, account_id: AccountId, pid: u128) -> u128 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap().clone();
+        let mut claimed = 0;
+        // backwards, so a position deleted after its claim only moves one already claimed
+        for (index, position) in transaction.iter().enumerate().rev() {
+            if position.transaction_type == TransactionType::Staking {
+                claimed += self.transfer_rewards(account_id.clone(), pid, index, 0, position.amount);
+            }
+        }
+        return claimed;
+    }
+
+    /// Burns the pool's claim fee from the claimer, `resolve_claim_burn` pays position `index`
+    /// or every position for `None`
+    fn _burn_for_claim(account_id: AccountId, pid: u128, index: Option<usize>, duration: u64, burn_token: AccountId, burn_amount: u128) {
+        ext_ft::ext(burn_token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+            .ft_burn(account_id.clone(), burn_amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .resolve_claim_burn(account_id, pid, index, duration)
+            );
+    }
+
+    /// Settles the position and books what the reserve can pay as paid out, without transferring it
+    fn _book_rewards(&mut self, receiver_id: AccountId, pid: u128, index: usize, duration: u64, amount: u128) -> u128 {
         // let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().clone();
         let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
         let transaction = self.user_info.entry(pid).or_default().entry(receiver_id.clone()).or_default();
@@ -462,42 +1692,47 @@ impl Contract {
         }
         
         assert!(amount <= transaction[index].amount, "Amount greater than transaction");
-        
-        let claimable_rewards;
-        if reward > transaction[index].paid_out {
-            claimable_rewards = reward - transaction[index].paid_out;
-        } else {
-            claimable_rewards = 0;
+
+        Self::_update_reward_per_token(pool);
+        Self::_settle_pending_reward(pool, &mut transaction[index]);
+        let claimable_rewards = transaction[index].unpaid_reward;
+        transaction[index].last_claimed_at = env::block_timestamp_ms();
+
+        if pool.mint_rewards {
+            pool.funds.total_rewards_paid += claimable_rewards;
+            transaction[index].paid_out += claimable_rewards;
+            transaction[index].unpaid_reward = 0;
+            return claimable_rewards;
         }
 
-        ext_ft::ext(pool.token_info.token.clone())
-            .with_static_gas(FT_TRANSFER_GAS)
-            .with_attached_deposit(DEPOSIT_ONE_YOCTO)
-            .ft_transfer(
-                receiver_id.clone().to_string(),
-                claimable_rewards.to_string(),
-                Some("0".to_string()),
-            );
+        // never pay out more than the reserve holds, the rest stays owed on the position
+        let paid_rewards = std::cmp::min(claimable_rewards, pool.funds.reward_reserve);
+        if paid_rewards < claimable_rewards {
+            events::log_reward_shortfall(&receiver_id, pid, index, claimable_rewards, paid_rewards);
+        }
 
-        transaction[index].paid_out += claimable_rewards;
+        pool.funds.reward_reserve -= paid_rewards;
+        pool.funds.total_rewards_paid += paid_rewards;
+        transaction[index].paid_out += paid_rewards;
+        transaction[index].unpaid_reward = claimable_rewards - paid_rewards;
 
-        return claimable_rewards;
+        return paid_rewards;
     }
 }
 
 // view
 #[near_bindgen]
 impl Contract {
+    pub fn version(&self) -> String {
+        return self.version.clone();
+    }
+
     pub fn total_pools(&self) -> usize {
         return self.pool_info.len();
     }
 
     pub fn pool_info(&self, pid: usize) -> PoolInfo {
-        let mut pool = self.pool_info.get(pid).unwrap().clone();
-        ext_ft::ext(pool.token_info.token.clone()).ft_metadata().then(
-            Self::ext(env::current_account_id()).ft_metadata_callback(&mut pool)
-        );
-        return pool;
+        return self.pool_info.get(pid).unwrap().clone();
     }
 
     pub fn calculate_interest(&self, user: AccountId, pid: u128, index: usize, amount: u128) -> u128 {
@@ -508,6 +1743,15 @@ impl Contract {
 
         if pool.pool_type == PoolType::Staking && env::block_timestamp_ms() < pool.deposit_limiters.end_time {
             return 0;
+        } else if transaction[index].transaction_type == TransactionType::Staking {
+            // staking rewards come from the pool accumulator, everything the position earned so far
+            let position = &transaction[index];
+            let accrued = mul_div(position.amount, Self::_reward_per_token(&pool), REWARD_PRECISION);
+            let earned = position.paid_out + position.unpaid_reward + accrued.saturating_sub(position.reward_debt);
+            if amount == position.amount {
+                return earned;
+            }
+            return earned * amount / position.amount;
         } else {
             let utilisation: u128;
             if pool.pool_type == PoolType::Loan {
@@ -517,14 +1761,235 @@ impl Contract {
             }
 
             let reward_calc_start_time: u64;            
+            let mut reward_calc_end_time = env::block_timestamp_ms();
             if pool.pool_type == PoolType::Loan {
-                reward_calc_start_time = transaction[index].time;
+                reward_calc_start_time = transaction[index].accrual_start;
             } else {
                 reward_calc_start_time = pool.deposit_limiters.end_time;
+                // staking rewards stop accruing once the reward duration is over
+                reward_calc_end_time = std::cmp::min(reward_calc_end_time, pool.deposit_limiters.end_time + pool.deposit_limiters.duration);
+            }
+
+            let paused_time = Self::_paused_reward_time(&pool) - transaction[index].paused_offset;
+            let accrual_time = (reward_calc_end_time - reward_calc_start_time).saturating_sub(paused_time);
+
+            let interest = amount * Self::_interest_rate_bps(&pool, utilisation) * accrual_time as u128 / (10_000 * 365 * ONE_DAY);
+
+            // short loans would round down to no interest at all
+            if transaction[index].transaction_type == TransactionType::Borrow && amount > 0 {
+                return Self::_cap_interest(&pool, amount, std::cmp::max(interest, pool.min_interest));
             }
+            return interest;
+        }
+    }
+
+    /// Interest `amount` would earn over `duration_ms` at the pool's current rate, capped at the
+    /// reward duration for staking pools. Loans use today's utilisation and the minimum interest,
+    /// fixed budget pools today's balance.
+    pub fn simulate_rewards(&self, pid: u128, amount: u128, duration_ms: u64) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+
+        let mut accrual_time = duration_ms;
+        if pool.pool_type == PoolType::Staking {
+            accrual_time = std::cmp::min(accrual_time, pool.deposit_limiters.duration);
+        }
+
+        // a fixed budget is shared with everything already staked
+        if let RewardMode::FixedBudget { total_reward, period } = pool.reward_mode {
+            let released = mul_div(total_reward, std::cmp::min(accrual_time, period) as u128, period as u128);
+            return U128(mul_div(released, amount, pool.funds.balance + amount));
+        }
+
+        let interest = amount * Self::_interest_rate_bps(pool, Self::_reward_utilisation(pool)) * accrual_time as u128 / (10_000 * 365 * ONE_DAY);
+        if pool.pool_type == PoolType::Loan && amount > 0 {
+            return U128(Self::_cap_interest(pool, amount, std::cmp::max(interest, pool.min_interest)));
+        }
+        return U128(interest);
+    }
+
+    /// `(already_paid, currently_claimable)` rewards of a position
+    pub fn position_yield(&self, pid: u128, user: AccountId, index: usize) -> (U128, U128) {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+
+        let paid_out = transaction[index].paid_out;
+        let reward = self.calculate_interest(user, pid, index, transaction[index].amount);
+        return (U128(paid_out), U128(reward.saturating_sub(paid_out)));
+    }
+
+    /// `(index, principal, claimable_reward)` of the user's staked positions that can be withdrawn now
+    pub fn claimable_positions(&self, pid: u128, user: AccountId) -> Vec<(usize, U128, U128)> {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let unlock_time = Self::_unlock_time(pool);
+
+        let transaction = match self.user_info.get(&pid).and_then(|users| users.get(&user)) {
+            Some(transaction) => transaction,
+            None => return Vec::new(),
+        };
+        if env::block_timestamp_ms() < unlock_time {
+            return Vec::new();
+        }
+
+        return transaction.iter()
+            .enumerate()
+            .filter(|(_, position)| position.transaction_type == TransactionType::Staking)
+            .map(|(index, position)| {
+                let reward = self.calculate_interest(user.clone(), pid, index, position.amount);
+                (index, U128(position.amount), U128(reward.saturating_sub(position.paid_out)))
+            })
+            .collect();
+    }
+
+    /// What `user` can still put into the pool: the smaller of their room under `limit_per_user`
+    /// (counting everything they have staked) and the capacity left
+    pub fn available_to_deposit(&self, pid: u128, user: AccountId) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let staked = self.total_user_amount_staked.get(&pid).and_then(|staked| staked.get(&user)).cloned().unwrap_or(0);
+
+        let user_room = pool.deposit_limiters.limit_per_user.saturating_sub(staked);
+        let capacity_left = pool.deposit_limiters.capacity.saturating_sub(pool.funds.balance);
+        return U128(std::cmp::min(user_room, capacity_left));
+    }
+
+    /// `(start_time, end_time, currently_open)` of the deposit window, loan pools are always open
+    pub fn deposit_window(&self, pid: u128) -> (u64, u64, bool) {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let now = env::block_timestamp_ms();
+
+        let open = pool.pool_type != PoolType::Staking
+            || (now >= pool.deposit_limiters.start_time && now <= pool.deposit_limiters.end_time);
+        return (pool.deposit_limiters.start_time, pool.deposit_limiters.end_time, open);
+    }
+
+    /// Principal still owed on a loan, partial repayments have already been taken off
+    pub fn remaining_principal(&self, pid: u128, user: AccountId, index: usize) -> U128 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Borrow, "not borrwed");
+
+        return U128(transaction[index].amount);
+    }
+
+    /// Principal plus the interest due right now, i.e. what repays the loan in full
+    pub fn full_repay_amount(&self, pid: u128, user: AccountId, index: usize) -> U128 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Borrow, "not borrwed");
+
+        let principal = transaction[index].amount;
+        return U128(principal + self.calculate_interest(user, pid, index, principal));
+    }
 
-            return amount * pool.apy * utilisation * (env::block_timestamp_ms() as u128 - reward_calc_start_time as u128) / (100 * 100 * 365 * ONE_DAY);
+    /// `collateral * 100 / (principal + interest)` of a loan, below 100 means the debt exceeds
+    /// the collateral. That is the borrow capacity of posted collateral when the pool accepts any,
+    /// otherwise loans are unsecured and the borrower's own stake in the pool counts as collateral.
+    pub fn health_factor(&self, pid: u128, user: AccountId, index: usize) -> U128 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Borrow, "not borrwed");
+
+        let principal = transaction[index].amount;
+        let debt = principal + self.calculate_interest(user.clone(), pid, index, principal);
+        if debt == 0 {
+            return U128(u128::MAX);
         }
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let collateral = if pool.token_info.accepted_collateral.is_empty() {
+            self.total_user_amount_staked.get(&pid).and_then(|staked| staked.get(&user)).cloned().unwrap_or(0)
+        } else {
+            self.borrow_capacity(pid, user).0
+        };
+        return U128(collateral * 100 / debt);
+    }
+
+    /// `(token, amount)` of collateral the borrower posted to the pool
+    pub fn posted_collateral(&self, pid: u128, user: AccountId) -> Vec<(AccountId, U128)> {
+        let mut posted: Vec<(AccountId, U128)> = self.posted_collateral.get(&pid)
+            .and_then(|borrowers| borrowers.get(&user))
+            .map(|posted| posted.iter().map(|(token, amount)| (token.clone(), U128(*amount))).collect())
+            .unwrap_or_default();
+        posted.sort_by(|a, b| a.0.cmp(&b.0));
+        return posted;
+    }
+
+    /// Principal the borrower's posted collateral supports, each token counted at its collateral factor
+    pub fn borrow_capacity(&self, pid: u128, user: AccountId) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let posted = match self.posted_collateral.get(&pid).and_then(|borrowers| borrowers.get(&user)) {
+            Some(posted) => posted,
+            None => return U128(0),
+        };
+
+        let mut capacity = 0;
+        for (token, factor_bps) in pool.token_info.accepted_collateral.iter() {
+            capacity += posted.get(token).cloned().unwrap_or(0) * factor_bps / 10_000;
+        }
+        return U128(capacity);
+    }
+
+    /// Amount of the position integrated over the time it was held (amount * ms), for splitting
+    /// a fixed reward by time-weighted balance
+    pub fn time_weighted_balance(&self, pid: u128, user: AccountId, index: usize) -> U128 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+
+        let position = &transaction[index];
+        let elapsed = env::block_timestamp_ms().saturating_sub(position.twab_updated_at);
+        return U128(position.twab + position.amount * elapsed as u128);
+    }
+
+    /// Milliseconds since the loan was opened, partial repays don't reset it
+    pub fn loan_age(&self, pid: u128, user: AccountId, index: usize) -> u64 {
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Borrow, "not borrwed");
+
+        return env::block_timestamp_ms() - transaction[index].accrual_start;
+    }
+
+    /// `(index, quarters)` of the user's staking positions with quarters passed since each one's
+    /// last payout, empty for non-quarterly pools. Claiming one position leaves the others claimable.
+    pub fn claimable_quarters(&self, pid: u128, user: AccountId) -> Vec<(usize, u64)> {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        if !pool.quarterly_payout || pool.pool_type != PoolType::Staking {
+            return Vec::new();
+        }
+
+        let quarters_at = |time: u64| -> u64 {
+            let elapsed = std::cmp::min(time.saturating_sub(pool.deposit_limiters.end_time), pool.deposit_limiters.duration);
+            return elapsed / QUARTER_DAY;
+        };
+        let now = quarters_at(env::block_timestamp_ms());
+        let transaction = self.user_info.get(&pid).and_then(|users| users.get(&user)).cloned().unwrap_or_default();
+        return transaction.iter()
+            .enumerate()
+            .filter(|(_, position)| position.transaction_type == TransactionType::Staking && position.amount > 0)
+            .map(|(index, position)| (index, now - quarters_at(position.last_claimed_at)))
+            .filter(|(_, unclaimed)| *unclaimed > 0)
+            .collect();
+    }
+
+    /// When the deposit unlocks, the same `_unlock_time` withdrawals and claims go by:
+    /// `end_time + duration` in staking pools, `end_time` in loan pools
+    pub fn unlock_time(&self, pid: u128, user: AccountId, index: usize) -> u64 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+        assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+
+        return Self::_unlock_time(pool);
+    }
+
+    /// True once a staking position stopped accruing (`end_time + duration`), loans never finalize
+    pub fn rewards_finalized(&self, pid: u128, user: AccountId, index: usize) -> bool {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.get(&pid).unwrap().get(&user).unwrap();
+        assert!(index < transaction.len(), "invalid position index");
+
+        if pool.pool_type != PoolType::Staking {
+            return false;
+        }
+        return env::block_timestamp_ms() >= pool.deposit_limiters.end_time + pool.deposit_limiters.duration;
     }
 
     pub fn get_pool_utilisation(&self, pid: u128) -> u128 {
@@ -546,16 +2011,292 @@ impl Contract {
         let mut t_pool_info: Vec<PoolInfo> = Vec::new();
         
         for i in from..to {
-            let mut pool = self.pool_info.get(usize::try_from(i).unwrap()).unwrap().clone();
-            ext_ft::ext(pool.token_info.token.clone()).ft_metadata().then(
-                Self::ext(env::current_account_id()).ft_metadata_callback(&mut pool)
-            );
+            let pool = self.pool_info.get(usize::try_from(i).unwrap()).unwrap().clone();
             t_pool_info.push(pool);
         }
 
         return t_pool_info;
     }
 
+    /// Pools in the requested order, unknown pids are skipped
+    pub fn pools_by_ids(&self, pids: Vec<u128>) -> Vec<PoolInfo> {
+        assert!(pids.len() <= MAX_BATCH_QUERY, "too many pools requested");
+
+        return pids.iter()
+            .filter_map(|pid| usize::try_from(*pid).ok().and_then(|pid| self.pool_info.get(pid)))
+            .cloned()
+            .collect();
+    }
+
+    /// `(name, symbol, decimals)` of the staked token as cached on the pool
+    pub fn token_display(&self, pid: u128) -> (String, String, u8) {
+        let token_info = &self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().token_info;
+        return (token_info.name.clone(), token_info.symbol.clone(), token_info.decimals);
+    }
+
+    /// Decimals of reward amounts: the owner override if set, the cached token metadata otherwise.
+    /// Display metadata only, rewards are paid in the staked token and never rescaled.
+    pub fn reward_decimals(&self, pid: u128) -> u8 {
+        let token_info = &self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().token_info;
+        return token_info.reward_decimals.unwrap_or(token_info.decimals);
+    }
+
+    /// Yearly rate actually paid so far in basis points: rewards paid over the balance held through time
+    pub fn realized_apy(&self, pid: u128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let tvl_time = Self::_tvl_time(pool);
+        if tvl_time == 0 {
+            return U128(0);
+        }
+        return U128(pool.funds.total_rewards_paid * 10_000 * 365 * ONE_DAY / tvl_time);
+    }
+
+    pub fn rewards_paid(&self, pid: u128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        return U128(pool.funds.total_rewards_paid);
+    }
+
+    /// Percentage of what the pool holds (principal incl. loans out, plus reward reserve)
+    /// over what it owes (principal plus a conservative reward estimate).
+    pub fn solvency(&self, pid: u128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+
+        let mut obligations = pool.funds.balance;
+        if let Some(users) = self.user_info.get(&pid) {
+            for position in users.values().flatten() {
+                if position.transaction_type == TransactionType::Staking {
+                    obligations += self._max_unpaid_reward(pool, position);
+                }
+            }
+        }
+
+        if obligations == 0 {
+            return U128(u128::MAX);
+        }
+        return U128((pool.funds.balance + pool.funds.reward_reserve) * 100 / obligations);
+    }
+
+    /// Milliseconds until rewards accruing at the current APY or budget release rate use up
+    /// `reward_reserve`, `u64::MAX` when nothing is accruing.
+    pub fn reserve_runway(&self, pid: u128) -> u64 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+
+        // a fixed budget drains at total_reward / period until the budget window closes
+        if let RewardMode::FixedBudget { total_reward, period } = pool.reward_mode {
+            let budget_end = pool.deposit_limiters.end_time + period;
+            if total_reward == 0 || pool.rewards_paused || env::block_timestamp_ms() >= budget_end {
+                return u64::MAX;
+            }
+            let runway = mul_div(pool.funds.reward_reserve, period as u128, total_reward);
+            return u64::try_from(runway).unwrap_or(u64::MAX);
+        }
+
+        let mut utilisation = 100;
+        if pool.pool_type == PoolType::Loan {
+            utilisation = self.get_pool_utilisation(pid);
+        }
+
+        let accrual_per_year = pool.funds.balance * pool.apy * utilisation;
+        if accrual_per_year == 0 || pool.rewards_paused {
+            return u64::MAX;
+        }
+
+        let runway = pool.funds.reward_reserve * 100 * 100 * 365 * ONE_DAY / accrual_per_year;
+        return u64::try_from(runway).unwrap_or(u64::MAX);
+    }
+
+    pub fn borrower_count(&self, pid: u128) -> u128 {
+        return self.pool_info.get(usize::try_from(pid).unwrap()).unwrap().active_borrowers;
+    }
+
+    /// `(token, held, owed)` from the last `refresh_global_solvency`, where owed is the principal
+    /// still in the contract plus reward reserves and fees of every pool using the token
+    pub fn global_solvency(&self) -> Vec<(AccountId, U128, U128)> {
+        let mut snapshot: Vec<(AccountId, U128, U128)> = self.solvency_snapshot.iter()
+            .map(|(token, (held, owed))| (token.clone(), U128(*held), U128(*owed)))
+            .collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        return snapshot;
+    }
+
+    /// Principal owed to depositors per pool token, loan pool balances include what is lent out
+    pub fn liabilities_by_token(&self) -> HashMap<AccountId, U128> {
+        let mut liabilities: HashMap<AccountId, U128> = HashMap::new();
+        for pool in self.pool_info.iter() {
+            let total = liabilities.entry(pool.token_info.token.clone()).or_insert(U128(0));
+            total.0 += pool.funds.balance;
+        }
+        return liabilities;
+    }
+
+    /// Withdrawal and origination fees the pool has kept so far, in the pool token
+    pub fn accrued_fees(&self, pid: u128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        return U128(pool.funds.fees_collected);
+    }
+
+    /// `accrued_fees` of every pool summed per pool token
+    pub fn total_accrued_fees(&self) -> HashMap<AccountId, U128> {
+        let mut fees: HashMap<AccountId, U128> = HashMap::new();
+        for pool in self.pool_info.iter() {
+            let total = fees.entry(pool.token_info.token.clone()).or_insert(U128(0));
+            total.0 += pool.funds.fees_collected;
+        }
+        return fees;
+    }
+
+    /// Distinct tokens rewards can be paid in, rewards are paid in each pool's staked token
+    pub fn reward_tokens(&self) -> Vec<AccountId> {
+        let mut tokens: Vec<AccountId> = self.pool_info.iter()
+            .map(|pool| pool.token_info.token.clone())
+            .collect();
+        tokens.sort();
+        tokens.dedup();
+        return tokens;
+    }
+
+    /// Pools referencing `token` as staked, collateral or accepted collateral token, rewards are paid in the staked token
+    pub fn pools_using_token(&self, token: AccountId) -> Vec<usize> {
+        return self.pool_info.iter()
+            .enumerate()
+            .filter(|(_, pool)| {
+                pool.token_info.token == token
+                    || pool.token_info.collateral_token == token
+                    || pool.token_info.accepted_collateral.iter().any(|(accepted, _)| *accepted == token)
+            })
+            .map(|(pid, _)| pid)
+            .collect();
+    }
+
+    /// Accounting invariants of the pool that do not hold, empty when the pool is healthy
+    pub fn check_invariants(&self, pid: u128) -> Vec<String> {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let mut violations: Vec<String> = Vec::new();
+
+        let mut staked = 0;
+        let mut borrowed = 0;
+        if let Some(users) = self.user_info.get(&pid) {
+            for position in users.values().flatten() {
+                match position.transaction_type {
+                    TransactionType::Staking => staked += position.amount,
+                    TransactionType::Borrow => borrowed += position.amount,
+                }
+            }
+        }
+        let pool_users = self.is_pool_user.get(&pid).map(|users| users.values().filter(|is_user| **is_user).count()).unwrap_or(0);
+
+        if staked != pool.funds.balance {
+            violations.push(format!("staked positions {} != balance {}", staked, pool.funds.balance));
+        }
+        if borrowed != pool.funds.loaned_balance {
+            violations.push(format!("borrowed positions {} != loaned_balance {}", borrowed, pool.funds.loaned_balance));
+        }
+        if pool.funds.loaned_balance > pool.funds.balance {
+            violations.push(format!("loaned_balance {} > balance {}", pool.funds.loaned_balance, pool.funds.balance));
+        }
+        if pool.unique_users != pool_users as u128 {
+            violations.push(format!("unique_users {} != pool users {}", pool.unique_users, pool_users));
+        }
+        return violations;
+    }
+
+    pub fn average_stake(&self, pid: u128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+
+        if pool.unique_users == 0 {
+            return U128(0);
+        }
+        return U128(pool.funds.balance / pool.unique_users);
+    }
+
+    /// Pids whose balance has reached `threshold_bps` of their capacity
+    pub fn pools_near_capacity(&self, threshold_bps: u128) -> Vec<usize> {
+        let mut pids: Vec<usize> = Vec::new();
+
+        for (pid, pool) in self.pool_info.iter().enumerate() {
+            if pool.deposit_limiters.capacity == 0 {
+                continue;
+            }
+            if pool.funds.balance * 10000 / pool.deposit_limiters.capacity >= threshold_bps {
+                pids.push(pid);
+            }
+        }
+
+        return pids;
+    }
+
+    /// Raw per-user bookkeeping for incident response
+    pub fn debug_user_state(&self, pid: u128, user: AccountId) -> DebugUserState {
+        DebugUserState {
+            is_pool_user: self.is_pool_user.get(&pid).and_then(|users| users.get(&user)).cloned().unwrap_or(false),
+            is_whitelisted: self.is_whitelisted.get(&pid).and_then(|users| users.get(&user)).cloned().unwrap_or(false),
+            total_staked: U128(self.total_user_amount_staked.get(&pid).and_then(|users| users.get(&user)).cloned().unwrap_or(0)),
+            total_borrowed: U128(self.total_user_amount_borrowed.get(&pid).and_then(|users| users.get(&user)).cloned().unwrap_or(0)),
+            positions: self.user_info.get(&pid).and_then(|users| users.get(&user)).map(|positions| positions.len()).unwrap_or(0),
+        }
+    }
+
+    pub fn is_operator(&self, owner: AccountId, operator: AccountId) -> bool {
+        return self.operators.get(&owner).and_then(|operators| operators.get(&operator)).cloned().unwrap_or(false);
+    }
+
+    pub fn is_borrow_operator(&self, owner: AccountId, operator: AccountId) -> bool {
+        return self.borrow_operators.get(&owner).and_then(|operators| operators.get(&operator)).cloned().unwrap_or(false);
+    }
+
+    /// Staked totals of `users` in input order, 0 for unknown accounts
+    pub fn staked_balances(&self, pid: u128, users: Vec<AccountId>) -> Vec<U128> {
+        assert!(users.len() <= MAX_BATCH_QUERY, "too many users requested");
+        let staked = self.total_user_amount_staked.get(&pid);
+
+        return users.iter()
+            .map(|user| U128(staked.and_then(|staked| staked.get(user)).cloned().unwrap_or(0)))
+            .collect();
+    }
+
+    /// The `n` largest staking positions of the pool as `(owner, index, amount)`, largest first
+    pub fn top_positions(&self, pid: u128, n: u32) -> Vec<(AccountId, usize, U128)> {
+        let n = usize::try_from(n).unwrap();
+        assert!(n <= MAX_BATCH_QUERY, "too many positions requested");
+
+        let mut positions: Vec<(AccountId, usize, U128)> = Vec::new();
+        if let Some(users) = self.user_info.get(&pid) {
+            for (user, transaction) in users.iter() {
+                for (index, position) in transaction.iter().enumerate() {
+                    if position.transaction_type == TransactionType::Staking {
+                        positions.push((user.clone(), index, U128(position.amount)));
+                    }
+                }
+            }
+        }
+
+        positions.sort_by(|a, b| b.2.0.cmp(&a.2.0).then_with(|| a.0.cmp(&b.0)).then_with(|| a.1.cmp(&b.1)));
+        positions.truncate(n);
+        return positions;
+    }
+
+    /// Outstanding loans of the pool as `(borrower, index, principal, opened at)`, ordered by borrower
+    /// and index so `from..to` pages stay stable. Partial repayments don't move the open time.
+    pub fn open_loans(&self, pid: u128, from: u128, to: u128) -> Vec<(AccountId, usize, U128, u64)> {
+        let from = usize::try_from(from).unwrap();
+        let to = usize::try_from(to).unwrap();
+        assert!(to.saturating_sub(from) <= MAX_BATCH_QUERY, "too many loans requested");
+
+        let mut loans: Vec<(AccountId, usize, U128, u64)> = Vec::new();
+        if let Some(users) = self.user_info.get(&pid) {
+            for (user, transaction) in users.iter() {
+                for (index, position) in transaction.iter().enumerate() {
+                    if position.transaction_type == TransactionType::Borrow {
+                        loans.push((user.clone(), index, U128(position.amount), position.accrual_start));
+                    }
+                }
+            }
+        }
+
+        loans.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        return loans.into_iter().skip(from).take(to.saturating_sub(from)).collect();
+    }
+
     pub fn total_stakes_of_user(&self, pid: u128, user:AccountId) -> usize {
         return self.user_info.get(&pid).unwrap().get(&user).unwrap().len();
     }
@@ -579,26 +2320,342 @@ impl Contract {
     #[private]
     pub fn ft_metadata_callback(
         &mut self,
-        pool_info: &mut PoolInfo,
+        pid: u128,
         #[callback_unwrap] meta: FungibleTokenMetadata,
     ) {
-        pool_info.token_info.decimals = meta.decimals;
-        pool_info.token_info.name = meta.name;
-        pool_info.token_info.symbol = meta.symbol;
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        // reward and collateral amounts are scaled by the decimals, keep the stored ones over implausible metadata
+        if meta.decimals > 0 && meta.decimals <= MAX_TOKEN_DECIMALS {
+            pool.token_info.decimals = meta.decimals;
+        } else {
+            events::log_implausible_decimals(pid, &pool.token_info.token, meta.decimals, pool.token_info.decimals);
+        }
+        pool.token_info.name = meta.name;
+        pool.token_info.symbol = meta.symbol;
+    }
+
+    #[private]
+    pub fn verify_collateral_minter_callback(
+        &self,
+        pid: u128,
+        #[callback_unwrap] minter: Option<AccountId>,
+    ) {
+        assert_eq!(minter, Some(env::current_account_id()), "pool {} is not the collateral minter", pid);
+    }
+
+    /// Credits a failed withdraw back as a new position of the account holding the principal and
+    /// the unpaid reward. The original index may already be reused, so it is not restored in place.
+    #[private]
+    pub fn withdraw_callback(
+        &mut self,
+        account_id: AccountId,
+        pid: u128,
+        amount: U128,
+        fee: U128,
+        rewards: U128,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) {
+        if transfer.is_ok() {
+            return;
+        }
+
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
+
+        Self::_update_reward_per_token(pool);
+        let mut user_info = UserInfo {
+            transaction_type: TransactionType::Staking,
+            amount: amount.0,
+            time: env::block_timestamp_ms(),
+            accrual_start: env::block_timestamp_ms(),
+            paused_offset: Self::_paused_reward_time(pool),
+            paid_out: 0,
+            unpaid_reward: rewards.0,
+            reward_debt: 0,
+            withdrawn: 0,
+            twab: 0,
+            twab_updated_at: env::block_timestamp_ms(),
+            last_claimed_at: 0,
+        };
+        Self::_reset_reward_debt(pool, &mut user_info);
+        transaction.push(user_info);
+
+        pool.funds.balance += amount.0;
+        Self::_sync_deposits_closed(pid, pool);
+        pool.funds.fees_collected -= fee.0;
+        pool.funds.reward_reserve += rewards.0;
+        pool.funds.total_rewards_paid -= rewards.0;
+
+        let total_user_amount_staked = self.total_user_amount_staked.entry(pid).or_default().entry(account_id.clone()).or_default();
+        *total_user_amount_staked = *total_user_amount_staked + amount.0;
+
+        let is_pool_user = self.is_pool_user.entry(pid).or_default().entry(account_id.clone()).or_default();
+        if *is_pool_user == false {
+            pool.unique_users += 1;
+        }
+        *is_pool_user = true;
+
+        Self::_restore_collateral(pool, &account_id, amount.0);
+    }
+
+    #[private]
+    pub fn deposit_split_callback(
+        &mut self,
+        staker: AccountId,
+        token: AccountId,
+        allocations: Vec<(u128, u128)>,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) {
+        assert!(transfer.is_ok(), "deposit transfer failed");
+        let total: u128 = allocations.iter().map(|(_, amount)| amount).sum();
+
+        // a pool may have closed or filled while the transfer was in flight, credit all or nothing
+        let mut unused = total;
+        if self._can_take_split(&staker, &token, &allocations) {
+            unused = 0;
+            for (pid, amount) in allocations {
+                unused += self.internal_deposit_and_stake(staker.clone(), pid, token.clone(), amount);
+            }
+        }
+
+        if unused > 0 {
+            ext_ft::ext(token)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+                .ft_transfer(staker.to_string(), unused.to_string(), Some("0".to_string()));
+        }
+    }
+
+    /// Drops a loan whose payout failed. Positions move when others are deleted, so the loan is
+    /// looked up by its open time and principal, a loan already repaid meanwhile is left alone.
+    #[private]
+    pub fn resolve_borrow(
+        &mut self,
+        account_id: AccountId,
+        pid: u128,
+        amount: U128,
+        fee: U128,
+        opened_at: u64,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) {
+        if transfer.is_ok() {
+            return;
+        }
+
+        let transaction = self.user_info.entry(pid).or_default().entry(account_id.clone()).or_default();
+        let index = match transaction.iter().position(|position| {
+            position.transaction_type == TransactionType::Borrow
+                && position.accrual_start == opened_at
+                && position.amount == amount.0
+        }) {
+            Some(index) => index,
+            None => return,
+        };
+        transaction[index].amount = 0;
+
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        Self::_update_reward_per_token(pool);
+        pool.funds.loaned_balance -= amount.0;
+        pool.funds.fees_collected -= fee.0;
+
+        let total_user_amount_borrowed = self.total_user_amount_borrowed.entry(pid).or_default().entry(account_id.clone()).or_default();
+        *total_user_amount_borrowed = *total_user_amount_borrowed - amount.0;
+        if *total_user_amount_borrowed == 0 {
+            pool.active_borrowers -= 1;
+        }
+
+        self._delete_stake_if_empty(account_id, pid, index);
+    }
+
+    /// Pays a claim once its burn went through. A failed burn rejects the claim, the cooldown
+    /// it started still applies.
+    #[private]
+    pub fn resolve_claim_burn(
+        &mut self,
+        account_id: AccountId,
+        pid: u128,
+        index: Option<usize>,
+        duration: u64,
+        #[callback_result] burn: Result<(), PromiseError>,
+    ) -> U128 {
+        assert!(burn.is_ok(), "claim burn failed");
+
+        let claimed = match index {
+            Some(index) => {
+                let transaction = self.user_info.get(&pid).unwrap().get(&account_id).unwrap();
+                assert!(index < transaction.len(), "invalid position index");
+                assert!(transaction[index].transaction_type == TransactionType::Staking, "not staked");
+                let amount = transaction[index].amount;
+                self.transfer_rewards(account_id, pid, index, duration, amount)
+            }
+            None => self._claim_positions(account_id, pid),
+        };
+        return U128(claimed);
+    }
+
+    #[private]
+    pub fn repay_callback(
+        &mut self,
+        borrower: AccountId,
+        pid: u128,
+        index: usize,
+        amount: U128,
+        repay_amount: U128,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) {
+        assert!(transfer.is_ok(), "repayment transfer failed");
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let token = pool.token_info.token.clone();
+
+        // the loan may have been repaid or moved by a delete while the transfer was in flight
+        let loan_open = self.user_info.get(&pid).and_then(|users| users.get(&borrower))
+            .and_then(|transaction| transaction.get(index))
+            .map_or(false, |position| position.transaction_type == TransactionType::Borrow && position.amount >= repay_amount.0);
+
+        // interest kept accruing while the transfer was in flight, hand the tokens back
+        // rather than keeping them without settling the loan
+        if !loan_open || amount.0 < repay_amount.0 + self.calculate_interest(borrower.clone(), pid, index, repay_amount.0) {
+            ext_ft::ext(token)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+                .ft_transfer(borrower.to_string(), amount.0.to_string(), Some("0".to_string()));
+            return;
+        }
+
+        // utilisation may have dropped meanwhile and with it the interest, send back the difference
+        let overage = self.internal_repay(borrower.clone(), pid, index, token.clone(), amount.0, repay_amount.0);
+        if overage > 0 {
+            ext_ft::ext(token)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+                .ft_transfer(borrower.to_string(), overage.to_string(), Some("0".to_string()));
+        }
+    }
+
+    #[private]
+    pub fn global_solvency_callback(&mut self, token: AccountId, #[callback_unwrap] balance: U128) {
+        let owed = self._tracked_token_funds(&token);
+        self.solvency_snapshot.insert(token, (balance.0, owed));
+    }
+
+    /// Returns the collateral balance and logs `CollateralMismatch` when it differs from the stake
+    #[private]
+    pub fn collateral_balance_callback(&self, pid: u128, user: AccountId, #[callback_unwrap] balance: U128) -> U128 {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        let staked = self.total_user_amount_staked.get(&pid).and_then(|staked| staked.get(&user)).cloned().unwrap_or(0);
+        if balance.0 != Self::_collateral_amount(&pool.token_info, staked) {
+            events::log_collateral_mismatch(&user, pid, staked, balance.0);
+        }
+        return balance;
+    }
+
+    #[private]
+    pub fn recover_token_callback(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        #[callback_unwrap] balance: U128,
+    ) -> Promise {
+        let sweepable = balance.0.saturating_sub(self._tracked_token_funds(&token));
+        assert!(sweepable > 0, "nothing to recover above pool funds");
+
+        let amount = std::cmp::min(amount.0, sweepable);
+        Self::_recover_transfer(token, receiver_id, amount)
+    }
+
+    /// Mints the moved position's collateral to `to` once the sender's was burnt. If the burn
+    /// failed the position goes back to `from`, looked up by its anchor and amount since
+    /// positions move when others are deleted.
+    #[private]
+    pub fn resolve_transfer_position(
+        &mut self,
+        pid: u128,
+        from: AccountId,
+        to: AccountId,
+        amount: U128,
+        accrual_start: u64,
+        #[callback_result] burn: Result<(), PromiseError>,
+    ) {
+        let pool = self.pool_info.get(usize::try_from(pid).unwrap()).unwrap();
+        if burn.is_ok() {
+            ext_ft::ext(pool.token_info.collateral_token.clone())
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(DEPOSIT_ONE_YOCTO)
+                .ft_mint(to, Self::_collateral_amount(&pool.token_info, amount.0));
+            return;
+        }
+
+        let transaction = self.user_info.get(&pid).and_then(|users| users.get(&to)).cloned().unwrap_or_default();
+        let index = transaction.iter().rposition(|position| {
+            position.transaction_type == TransactionType::Staking
+                && position.accrual_start == accrual_start
+                && position.amount == amount.0
+        });
+        if let Some(index) = index {
+            self._move_position(pid, &to, &from, index);
+        }
+    }
+
+    /// Restores the fees when their transfer failed, resolves to whether they were sent
+    #[private]
+    pub fn resolve_withdraw_fees(
+        &mut self,
+        pid: u128,
+        amount: U128,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) -> bool {
+        if transfer.is_err() {
+            let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+            pool.funds.fees_collected += amount.0;
+        }
+        return transfer.is_ok();
+    }
+
+    #[private]
+    pub fn resolve_recover(
+        &self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        #[callback_result] transfer: Result<(), PromiseError>,
+    ) -> bool {
+        events::log_token_recovery(&token, &receiver_id, amount.0, transfer.is_ok());
+        return transfer.is_ok();
+    }
+}
+
+// test-only state setters, not exported to the contract interface
+#[cfg(feature = "test-utils")]
+impl Contract {
+    pub fn set_funds_for_testing(&mut self, pid: u128, balance: u128, loaned_balance: u128) {
+        let pool = self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap();
+        pool.funds.balance = balance;
+        pool.funds.loaned_balance = loaned_balance;
+    }
+
+    pub fn set_unique_users_for_testing(&mut self, pid: u128, unique_users: u128) {
+        self.pool_info.get_mut(usize::try_from(pid).unwrap()).unwrap().unique_users = unique_users;
     }
 }
 
 // modifier
 impl Contract {
     fn assert_caller_allowed(&self) {
-        if !self.is_owner() {
-            env::panic_str("Caller not allowed")
-        }
+        assert!(self.is_owner(), "Caller not allowed");
+    }
+
+    fn assert_owner_or_guardian(&self) {
+        assert!(self.is_owner() || self.is_guardian(), "Caller not allowed");
     }
 
     fn is_owner(&self) -> bool {
         env::signer_account_id() == env::current_account_id()
     }
+
+    fn is_guardian(&self) -> bool {
+        self.guardian.as_ref() == Some(&env::signer_account_id())
+    }
 }
 
 // callback for staking
@@ -618,14 +2675,31 @@ impl FungibleTokenReceiver for Contract {
         let mut result = 0;
         match messages[0].as_str() {
             "staking" => {
-                self.internal_deposit_and_stake(sender_id, pid, token_id, amount.0);
-                result = 1;
+                // refund rather than panic so the tokens go back to the sender
+                if !self._can_stake(pid, &sender_id) {
+                    return PromiseOrValue::Value(amount);
+                }
+                // "staking:<pid>:nonce=<n>", a replayed nonce is refunded untouched
+                if let Some(nonce) = messages.get(2).and_then(|x| x.trim().strip_prefix("nonce=")) {
+                    let nonce: u64 = nonce.parse().expect("should be number");
+                    let processed = self.processed_nonces.entry(sender_id.clone()).or_default().entry(nonce).or_default();
+                    if *processed {
+                        return PromiseOrValue::Value(amount);
+                    }
+                    *processed = true;
+                }
+                result = self.internal_deposit_and_stake(sender_id, pid, token_id, amount.0);
             }
             "borrow" => {
                 let index = messages[2].trim().parse().expect("should be number");
                 let repay_amount = messages[3].trim().parse().expect("should be number");
-                self.internal_repay(sender_id, pid, index, token_id, amount.0, repay_amount);
-                result = 2;
+                result = self.internal_repay(sender_id, pid, index, token_id, amount.0, repay_amount);
+            }
+            "reward" => {
+                self.internal_fund_rewards(pid, token_id, amount.0);
+            }
+            "collateral" => {
+                self.internal_post_collateral(sender_id, pid, token_id, amount.0);
             }
             _ => {
                 env::panic_str("wrong message format");
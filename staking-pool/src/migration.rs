@@ -0,0 +1,203 @@
+//! State layout of the first deployment, before the contract stored a `version`.
+//! Only read by `Contract::migrate`, which converts it to the current layout.
+
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize};
+use near_sdk::{env, AccountId};
+
+use crate::{
+    mul_div, Contract, DepositLimiters, Funds, InterestModel, PoolInfo, PoolType, RewardMode, TokenInfo,
+    TransactionType, UserInfo, ONE_DAY,
+};
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldUserInfo {
+    transaction_type: TransactionType,
+    amount: u128,
+    time: u64,
+    paid_out: u128,
+}
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldTokenInfo {
+    token: AccountId,
+    collateral_token: AccountId,
+    decimals: u8,
+    name: String,
+    symbol: String,
+}
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldDepositLimiters {
+    duration: u64,
+    start_time: u64,
+    end_time: u64,
+    limit_per_user: u128,
+    capacity: u128,
+    max_utilisation: u128,
+}
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldFunds {
+    balance: u128,
+    loaned_balance: u128,
+}
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldPoolInfo {
+    pool_name: String,
+    pool_type: PoolType,
+    apy: u128,
+    paused: bool,
+    quarterly_payout: bool,
+    unique_users: u128,
+    token_info: OldTokenInfo,
+    funds: OldFunds,
+    deposit_limiters: OldDepositLimiters,
+}
+
+#[derive(BorshDeserialize)]
+pub(crate) struct OldContract {
+    pool_info: Vec<OldPoolInfo>,
+    is_pool_user: HashMap<u128, HashMap<AccountId, bool>>,
+    is_whitelisted: HashMap<u128, HashMap<AccountId, bool>>,
+    user_info: HashMap<u128, HashMap<AccountId, Vec<OldUserInfo>>>,
+    total_user_amount_staked: HashMap<u128, HashMap<AccountId, u128>>,
+    total_user_amount_borrowed: HashMap<u128, HashMap<AccountId, u128>>,
+}
+
+impl OldPoolInfo {
+    fn into_current(self, active_borrowers: u128) -> PoolInfo {
+        let deposits_closed = self.funds.balance >= self.deposit_limiters.capacity;
+        PoolInfo {
+            pool_name: self.pool_name,
+            icon: None,
+            description: None,
+            pool_type: self.pool_type,
+            apy: self.apy,
+            reward_mode: RewardMode::Apy,
+            boost: None,
+            min_interest: 0,
+            max_interest_bps: 0,
+            withdraw_fee_bps: 0,
+            origination_fee_bps: 0,
+            interest_model: InterestModel::Linear,
+            claim_cooldown_ms: 0,
+            claim_burn: None,
+            collateral_burn: true,
+            collateral_sink: None,
+            outflow_limit: 0,
+            outflow_window_ms: 0,
+            outflow_window_start: 0,
+            outflow_volume: 0,
+            paused: self.paused,
+            deposits_closed,
+            gated: false,
+            rewards_paused: false,
+            rewards_paused_at: 0,
+            rewards_paused_duration: 0,
+            // the accumulator starts now, what positions earned before is settled into `unpaid_reward`
+            reward_per_token_stored: 0,
+            reward_updated_at: env::block_timestamp_ms(),
+            quarterly_payout: self.quarterly_payout,
+            mint_rewards: false,
+            unique_users: self.unique_users,
+            active_borrowers,
+            token_info: TokenInfo {
+                token: self.token_info.token,
+                collateral_token: self.token_info.collateral_token,
+                decimals: self.token_info.decimals,
+                reward_decimals: None,
+                collateral_decimals: None,
+                accepted_collateral: Vec::new(),
+                name: self.token_info.name,
+                symbol: self.token_info.symbol,
+            },
+            funds: Funds {
+                balance: self.funds.balance,
+                loaned_balance: self.funds.loaned_balance,
+                reward_reserve: 0,
+                total_rewards_paid: 0,
+                fees_collected: 0,
+                tvl_time: 0,
+            },
+            deposit_limiters: DepositLimiters {
+                duration: self.deposit_limiters.duration,
+                start_time: self.deposit_limiters.start_time,
+                end_time: self.deposit_limiters.end_time,
+                limit_per_user: self.deposit_limiters.limit_per_user,
+                capacity: self.deposit_limiters.capacity,
+                max_utilisation: self.deposit_limiters.max_utilisation,
+                min_reserve_bps: 0,
+                withdraw_buffer_bps: 0,
+                stake_cooldown_ms: 0,
+            },
+            archived: false,
+        }
+    }
+}
+
+impl OldUserInfo {
+    /// Staking positions keep what the old per-position formula owed them as `unpaid_reward`
+    fn into_current(self, pool: &PoolInfo) -> UserInfo {
+        let now = env::block_timestamp_ms();
+        let mut unpaid_reward = 0;
+        if self.transaction_type == TransactionType::Staking {
+            let accrual_time: u64;
+            let utilisation: u128;
+            if pool.pool_type == PoolType::Staking {
+                accrual_time = Contract::_clamp_to_accrual_window(pool, now) - pool.deposit_limiters.end_time;
+                utilisation = 100;
+            } else {
+                accrual_time = now.saturating_sub(self.time);
+                utilisation = Contract::_reward_utilisation(pool);
+            }
+            let earned = mul_div(self.amount, pool.apy * utilisation * accrual_time as u128, 100 * 100 * 365 * ONE_DAY);
+            unpaid_reward = earned.saturating_sub(self.paid_out);
+        }
+
+        UserInfo {
+            transaction_type: self.transaction_type,
+            amount: self.amount,
+            time: self.time,
+            accrual_start: self.time,
+            paused_offset: 0,
+            paid_out: self.paid_out,
+            unpaid_reward,
+            reward_debt: 0,
+            withdrawn: 0,
+            twab: 0,
+            twab_updated_at: now,
+            last_claimed_at: 0,
+        }
+    }
+}
+
+impl OldContract {
+    pub(crate) fn into_current(self) -> Contract {
+        let mut contract = Contract::new();
+
+        for (pid, pool) in self.pool_info.into_iter().enumerate() {
+            let pid = u128::try_from(pid).unwrap();
+            let active_borrowers = self.total_user_amount_borrowed.get(&pid)
+                .map(|borrowed| borrowed.values().filter(|amount| **amount > 0).count())
+                .unwrap_or(0);
+            contract.pool_info.push(pool.into_current(u128::try_from(active_borrowers).unwrap()));
+        }
+
+        for (pid, users) in self.user_info {
+            let pool = &contract.pool_info[usize::try_from(pid).unwrap()];
+            let users = users.into_iter()
+                .map(|(user, positions)| (user, positions.into_iter().map(|position| position.into_current(pool)).collect()))
+                .collect();
+            contract.user_info.insert(pid, users);
+        }
+
+        contract.is_pool_user = self.is_pool_user;
+        contract.is_whitelisted = self.is_whitelisted;
+        contract.total_user_amount_staked = self.total_user_amount_staked;
+        contract.total_user_amount_borrowed = self.total_user_amount_borrowed;
+        return contract;
+    }
+}
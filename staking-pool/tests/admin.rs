@@ -0,0 +1,662 @@
+mod setup;
+
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+use setup::*;
+use staking_pool::{PoolType, TransactionType};
+
+fn treasury() -> AccountId {
+    "treasury.near".parse().unwrap()
+}
+
+#[test]
+fn recover_token_sweeps_foreign_token_directly() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    let foreign: AccountId = "foreign.near".parse().unwrap();
+
+    as_owner(0);
+    contract.recover_token(foreign.clone(), 500, treasury());
+
+    let calls = scheduled_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].0, foreign);
+    assert_eq!(calls[0].1, "ft_transfer");
+    assert!(calls[0].2.contains("\"receiver_id\":\"treasury.near\""));
+    assert!(calls[0].2.contains("\"500\""));
+    assert_eq!(calls[1].1, "resolve_recover");
+}
+
+#[test]
+fn recover_token_checks_balance_for_pool_token() {
+    let mut contract = contract_with_pool(staking_pool_json());
+
+    as_owner(0);
+    contract.recover_token(token(), 500, treasury());
+
+    let methods: Vec<String> = scheduled_calls().into_iter().map(|call| call.1).collect();
+    assert_eq!(methods, vec!["ft_balance_of", "recover_token_callback"]);
+}
+
+#[test]
+fn recover_token_callback_caps_to_excess() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    fund_rewards(&mut contract, 0, 100);
+
+    as_owner(0);
+    contract.recover_token_callback(token(), treasury(), U128(1_000), U128(1_500));
+
+    let calls = scheduled_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].1, "ft_transfer");
+    assert!(calls[0].2.contains("\"receiver_id\":\"treasury.near\""));
+    assert!(calls[0].2.contains("\"400\""));
+    assert_eq!(calls[1].1, "resolve_recover");
+}
+
+fn usdc() -> AccountId {
+    "usdc.near".parse().unwrap()
+}
+
+fn pool_with_posted_collateral() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["token_info"]["accepted_collateral"] = near_sdk::serde_json::json!([[usdc(), 8_000]]);
+    let mut contract = contract_with_pool(pool);
+    transfer_call(&mut contract, &usdc(), &alice(), 100_000, "collateral:0", 0);
+    contract
+}
+
+#[test]
+fn recover_token_checks_balance_for_accepted_collateral() {
+    let mut contract = pool_with_posted_collateral();
+
+    as_owner(0);
+    contract.recover_token(usdc(), 500, treasury());
+
+    let methods: Vec<String> = scheduled_calls().into_iter().map(|call| call.1).collect();
+    assert_eq!(methods, vec!["ft_balance_of", "recover_token_callback"]);
+}
+
+#[test]
+fn recovering_collateral_token_is_capped_at_surplus() {
+    let mut contract = pool_with_posted_collateral();
+
+    as_owner(0);
+    contract.recover_token_callback(usdc(), treasury(), U128(100_000), U128(100_300));
+
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, usdc());
+    assert_eq!(calls[0].1, "ft_transfer");
+    assert!(calls[0].2.contains("\"receiver_id\":\"treasury.near\""));
+    assert!(calls[0].2.contains("\"300\""));
+}
+
+fn pool_with_fees() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["withdraw_fee_bps"] = near_sdk::serde_json::json!(100);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 10_000, START_TIME);
+    fund_rewards(&mut contract, 0, 10_000);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 10_000);
+    contract
+}
+
+#[test]
+fn withdraw_fees_sends_collected_fees() {
+    let mut contract = pool_with_fees();
+    assert_eq!(contract.accrued_fees(0).0, 100);
+
+    as_owner(END_TIME + ONE_YEAR);
+    contract.withdraw_fees(0, treasury());
+
+    assert_eq!(contract.accrued_fees(0).0, 0);
+    assert_eq!(as_u128(&admin_action("withdraw_fees")["pid"]), 0);
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, token());
+    assert_eq!(calls[0].1, "ft_transfer");
+    assert!(calls[0].2.contains("\"receiver_id\":\"treasury.near\""));
+    assert!(calls[0].2.contains("\"amount\":\"100\""));
+    assert_eq!(calls[1].1, "resolve_withdraw_fees");
+}
+
+#[test]
+fn failed_fee_withdrawal_restores_fees() {
+    let mut contract = pool_with_fees();
+    as_owner(END_TIME + ONE_YEAR);
+    contract.withdraw_fees(0, treasury());
+
+    assert!(!contract.resolve_withdraw_fees(0, U128(100), Err(near_sdk::PromiseError::Failed)));
+    assert_eq!(contract.accrued_fees(0).0, 100);
+}
+
+#[test]
+#[should_panic(expected = "Caller not allowed")]
+fn only_owner_withdraws_fees() {
+    let mut contract = pool_with_fees();
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw_fees(0, alice());
+}
+
+#[test]
+fn failed_recovery_is_logged() {
+    let contract = contract_with_pool(staking_pool_json());
+    let foreign: AccountId = "foreign.near".parse().unwrap();
+
+    as_owner(0);
+    assert!(!contract.resolve_recover(foreign.clone(), treasury(), U128(500), Err(near_sdk::PromiseError::Failed)));
+
+    let recoveries = events("TokenRecovery");
+    assert_eq!(recoveries.len(), 1);
+    assert_eq!(recoveries[0]["token"], foreign.to_string());
+    assert_eq!(recoveries[0]["receiver_id"], "treasury.near");
+    assert_eq!(recoveries[0]["amount"], "500");
+    assert_eq!(recoveries[0]["success"], false);
+}
+
+#[test]
+fn successful_recovery_is_logged() {
+    let contract = contract_with_pool(staking_pool_json());
+
+    as_owner(0);
+    assert!(contract.resolve_recover(token(), treasury(), U128(400), Ok(())));
+    assert_eq!(events("TokenRecovery")[0]["success"], true);
+}
+
+#[test]
+#[should_panic(expected = "nothing to recover above pool funds")]
+fn recover_token_callback_rejects_sweeping_pool_funds() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    fund_rewards(&mut contract, 0, 100);
+
+    as_owner(0);
+    contract.recover_token_callback(token(), treasury(), U128(1_000), U128(1_100));
+}
+
+#[test]
+#[should_panic(expected = "recovery receiver is the contract itself")]
+fn recover_token_rejects_the_contract_as_receiver() {
+    let mut contract = contract_with_pool(staking_pool_json());
+
+    as_owner(0);
+    contract.recover_token(token(), 10, pool_account());
+}
+
+#[test]
+#[should_panic(expected = "duration should be greater than zero")]
+fn create_staking_pool_rejects_zero_duration() {
+    let mut pool = staking_pool_json();
+    pool["deposit_limiters"]["duration"] = 0.into();
+    contract_with_pool(pool);
+}
+
+#[test]
+#[should_panic(expected = "end time already passed")]
+fn create_staking_pool_rejects_a_past_window() {
+    let mut contract = new_contract();
+    as_owner(END_TIME + 1);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+}
+
+#[test]
+fn create_loan_pool_ignores_a_past_window() {
+    let mut contract = new_contract();
+    as_owner(END_TIME + 1);
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+    assert_eq!(contract.total_pools(), 1);
+}
+
+#[test]
+fn create_loan_pool_ignores_duration() {
+    let mut pool = loan_pool_json();
+    pool["deposit_limiters"]["duration"] = 0.into();
+    let contract = contract_with_pool(pool);
+    assert_eq!(contract.total_pools(), 1);
+}
+
+#[test]
+#[should_panic(expected = "too many pools")]
+fn create_pool_rejects_pools_past_the_limit() {
+    let mut contract = new_contract();
+    for _ in 0..staking_pool::MAX_POOLS {
+        as_owner(0);
+        contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+    }
+    assert_eq!(contract.total_pools(), staking_pool::MAX_POOLS);
+    as_owner(0);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+}
+
+#[test]
+fn remove_pool_keeps_later_pids_stable() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    let mut second = staking_pool_json();
+    second["pool_name"] = "second".into();
+    as_owner(0);
+    contract.create_pool(pool_from_json(second), staking_pool::PoolType::Staking);
+    stake(&mut contract, &alice(), 1, 100, START_TIME);
+
+    as_owner(0);
+    contract.remove_pool(0);
+
+    assert_eq!(contract.total_pools(), 2);
+    assert_eq!(pool_json(&contract, 0)["archived"], true);
+    assert_eq!(pool_json(&contract, 1)["pool_name"], "second");
+    assert_eq!(contract.total_stakes_of_user(1, alice()), 1);
+}
+
+#[test]
+#[should_panic(expected = "pool not empty")]
+fn remove_pool_requires_empty_pool() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_owner(0);
+    contract.remove_pool(0);
+}
+
+#[test]
+#[should_panic(expected = "pool archived")]
+fn archived_pool_rejects_deposits() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.remove_pool(0);
+
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+}
+
+#[test]
+fn version_is_set_on_init() {
+    let contract = new_contract();
+    assert_eq!(contract.version(), staking_pool::VERSION);
+}
+
+// the state layout of the first deployment, as `migrate` finds it
+#[derive(BorshSerialize)]
+struct BaselineUserInfo {
+    transaction_type: TransactionType,
+    amount: u128,
+    time: u64,
+    paid_out: u128,
+}
+
+#[derive(BorshSerialize)]
+struct BaselineTokenInfo {
+    token: AccountId,
+    collateral_token: AccountId,
+    decimals: u8,
+    name: String,
+    symbol: String,
+}
+
+#[derive(BorshSerialize)]
+struct BaselineDepositLimiters {
+    duration: u64,
+    start_time: u64,
+    end_time: u64,
+    limit_per_user: u128,
+    capacity: u128,
+    max_utilisation: u128,
+}
+
+#[derive(BorshSerialize)]
+struct BaselineFunds {
+    balance: u128,
+    loaned_balance: u128,
+}
+
+#[derive(BorshSerialize)]
+struct BaselinePoolInfo {
+    pool_name: String,
+    pool_type: PoolType,
+    apy: u128,
+    paused: bool,
+    quarterly_payout: bool,
+    unique_users: u128,
+    token_info: BaselineTokenInfo,
+    funds: BaselineFunds,
+    deposit_limiters: BaselineDepositLimiters,
+}
+
+#[derive(BorshSerialize)]
+struct BaselineContract {
+    pool_info: Vec<BaselinePoolInfo>,
+    is_pool_user: HashMap<u128, HashMap<AccountId, bool>>,
+    is_whitelisted: HashMap<u128, HashMap<AccountId, bool>>,
+    user_info: HashMap<u128, HashMap<AccountId, Vec<BaselineUserInfo>>>,
+    total_user_amount_staked: HashMap<u128, HashMap<AccountId, u128>>,
+    total_user_amount_borrowed: HashMap<u128, HashMap<AccountId, u128>>,
+}
+
+fn baseline_pool(pool_name: &str, pool_type: PoolType, balance: u128, loaned_balance: u128) -> BaselinePoolInfo {
+    BaselinePoolInfo {
+        pool_name: pool_name.to_string(),
+        pool_type,
+        apy: 10,
+        paused: false,
+        quarterly_payout: false,
+        unique_users: 1,
+        token_info: BaselineTokenInfo {
+            token: token(),
+            collateral_token: collateral(),
+            decimals: 24,
+            name: "Token".to_string(),
+            symbol: "TKN".to_string(),
+        },
+        funds: BaselineFunds { balance, loaned_balance },
+        deposit_limiters: BaselineDepositLimiters {
+            duration: ONE_YEAR,
+            start_time: START_TIME,
+            end_time: END_TIME,
+            limit_per_user: 1_000_000,
+            capacity: 10_000_000,
+            max_utilisation: 80,
+        },
+    }
+}
+
+fn baseline_position(transaction_type: TransactionType, amount: u128) -> Vec<BaselineUserInfo> {
+    vec![BaselineUserInfo { transaction_type, amount, time: START_TIME, paid_out: 0 }]
+}
+
+#[test]
+fn migrate_converts_baseline_state() {
+    let baseline = BaselineContract {
+        pool_info: vec![
+            baseline_pool("staking", PoolType::Staking, 1_000_000, 0),
+            baseline_pool("loan", PoolType::Loan, 0, 500),
+        ],
+        is_pool_user: HashMap::from([
+            (0, HashMap::from([(alice(), true)])),
+            (1, HashMap::from([(bob(), true)])),
+        ]),
+        is_whitelisted: HashMap::from([(1, HashMap::from([(bob(), true)]))]),
+        user_info: HashMap::from([
+            (0, HashMap::from([(alice(), baseline_position(TransactionType::Staking, 1_000_000))])),
+            (1, HashMap::from([(bob(), baseline_position(TransactionType::Borrow, 500))])),
+        ]),
+        total_user_amount_staked: HashMap::from([(0, HashMap::from([(alice(), 1_000_000)]))]),
+        total_user_amount_borrowed: HashMap::from([(1, HashMap::from([(bob(), 500)]))]),
+    };
+
+    as_owner(END_TIME + ONE_YEAR / 2);
+    near_sdk::env::state_write(&baseline);
+    let contract = staking_pool::Contract::migrate();
+
+    assert_eq!(contract.version(), staking_pool::VERSION);
+    assert_eq!(contract.total_pools(), 2);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(pool["pool_name"], "staking");
+    assert_eq!(as_u128(&pool["funds"]["balance"]), 1_000_000);
+    assert_eq!(pool["reward_mode"], "Apy");
+    assert_eq!(as_u128(&pool_json(&contract, 1)["active_borrowers"]), 1);
+    assert_eq!(contract.total_stakes_of_user(1, bob()), 1);
+
+    // half a year at 10% was owed under the old formula and is kept, the accumulator adds the rest
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["unpaid_reward"]), 50_000);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), 100_000);
+}
+
+#[test]
+fn create_pool_verifies_collateral_minter() {
+    let mut contract = new_contract();
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, collateral());
+    assert_eq!(calls[0].1, "get_minter");
+    assert_eq!(calls[1].1, "verify_collateral_minter_callback");
+}
+
+#[test]
+fn authorized_collateral_minter_passes() {
+    let contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.verify_collateral_minter_callback(0, Some(pool_account()));
+}
+
+#[test]
+#[should_panic(expected = "pool 0 is not the collateral minter")]
+fn unauthorized_collateral_minter_fails() {
+    let contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.verify_collateral_minter_callback(0, Some(alice()));
+}
+
+#[test]
+fn duplicate_pool_names_allowed_by_default() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+    assert_eq!(contract.total_pools(), 2);
+}
+
+#[test]
+#[should_panic(expected = "pool name already exists")]
+fn duplicate_pool_names_rejected_when_unique() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.set_unique_pool_names(true);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+}
+
+fn same_token_pool_json() -> near_sdk::serde_json::Value {
+    let mut pool = staking_pool_json();
+    pool["token_info"]["collateral_token"] = token().to_string().into();
+    pool
+}
+
+#[test]
+#[should_panic(expected = "collateral token same as staked token")]
+fn staking_pool_rejects_staked_token_as_collateral() {
+    contract_with_pool(same_token_pool_json());
+}
+
+#[test]
+fn staking_pool_with_distinct_collateral_is_created() {
+    let contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.total_pools(), 1);
+}
+
+#[test]
+fn same_token_collateral_allowed_when_check_disabled() {
+    let mut contract = new_contract();
+    contract.set_distinct_collateral(false);
+    contract.create_pool(pool_from_json(same_token_pool_json()), staking_pool::PoolType::Staking);
+
+    let mut loan_pool = loan_pool_json();
+    loan_pool["token_info"]["collateral_token"] = token().to_string().into();
+    contract.set_distinct_collateral(true);
+    contract.create_pool(pool_from_json(loan_pool), staking_pool::PoolType::Loan);
+    assert_eq!(contract.total_pools(), 2);
+}
+
+fn admin_action(method: &str) -> near_sdk::serde_json::Value {
+    let actions: Vec<near_sdk::serde_json::Value> = events("AdminAction")
+        .into_iter()
+        .filter(|action| action["method"] == method)
+        .collect();
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0]["caller"], pool_account().to_string());
+    actions[0].clone()
+}
+
+#[test]
+fn admin_calls_are_logged() {
+    let mut contract = new_contract();
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+    assert_eq!(as_u128(&admin_action("create_pool")["pid"]), 0);
+
+    contract.edit_pool(0, pool_from_json(loan_pool_json()));
+    assert_eq!(as_u128(&admin_action("edit_pool")["pid"]), 0);
+
+    contract.set_pool_paused(0, true);
+    assert_eq!(as_u128(&admin_action("set_pool_paused")["pid"]), 0);
+
+    contract.whitelist(0, alice(), true);
+    assert_eq!(as_u128(&admin_action("whitelist")["pid"]), 0);
+
+    contract.recover_token("other.near".parse().unwrap(), 10, treasury());
+    assert!(admin_action("recover_token")["pid"].is_null());
+}
+
+#[test]
+fn reserve_funded_pool_warns_about_empty_reserve() {
+    contract_with_pool(staking_pool_json());
+    let warnings = events("RewardReserveUnfunded");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["pid"], "0");
+}
+
+#[test]
+fn minting_pool_needs_no_reserve() {
+    let mut pool = staking_pool_json();
+    pool["mint_rewards"] = true.into();
+    contract_with_pool(pool);
+    assert!(events("RewardReserveUnfunded").is_empty());
+}
+
+#[test]
+fn adjusting_a_stake_up_keeps_totals_consistent() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &bob(), 0, 500, START_TIME);
+
+    as_owner(START_TIME);
+    contract.admin_adjust_stake(0, alice(), 0, 1_200);
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 1_200);
+    assert_eq!(contract.staked_balances(0, vec![alice(), bob()]), vec![U128(1_200), U128(500)]);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 1_700);
+    assert!(contract.check_invariants(0).is_empty());
+
+    let adjustments = events("AdminAdjustment");
+    assert_eq!(adjustments[0]["old_amount"], "1000");
+    assert_eq!(adjustments[0]["new_amount"], "1200");
+}
+
+#[test]
+fn adjusting_a_stake_to_zero_removes_the_position() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &bob(), 0, 500, START_TIME);
+
+    as_owner(START_TIME);
+    contract.admin_adjust_stake(0, alice(), 0, 400);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 900);
+    contract.admin_adjust_stake(0, alice(), 0, 0);
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 500);
+    assert!(contract.check_invariants(0).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Caller not allowed")]
+fn only_the_owner_adjusts_stakes() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.admin_adjust_stake(0, alice(), 0, 5_000);
+}
+
+fn guardian() -> AccountId {
+    "guardian.near".parse().unwrap()
+}
+
+fn contract_with_guardian() -> staking_pool::Contract {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.set_guardian(Some(guardian()));
+    contract
+}
+
+#[test]
+fn guardian_can_pause_pools() {
+    let mut contract = contract_with_guardian();
+
+    as_user(&guardian(), 0);
+    contract.set_pool_paused(0, true);
+    assert_eq!(pool_json(&contract, 0)["paused"], true);
+}
+
+#[test]
+#[should_panic(expected = "only the owner can unpause")]
+fn guardian_cannot_unpause_pools() {
+    let mut contract = contract_with_guardian();
+    contract.set_pool_paused(0, true);
+
+    as_user(&guardian(), 0);
+    contract.set_pool_paused(0, false);
+}
+
+#[test]
+fn setting_guardian_is_logged() {
+    contract_with_guardian();
+    assert!(admin_action("set_guardian")["pid"].is_null());
+}
+
+#[test]
+#[should_panic(expected = "Caller not allowed")]
+fn guardian_cannot_recover_tokens() {
+    let mut contract = contract_with_guardian();
+
+    as_user(&guardian(), 0);
+    contract.recover_token(token(), 10, treasury());
+}
+
+#[test]
+#[should_panic(expected = "Caller not allowed")]
+fn guardian_cannot_edit_pools() {
+    let mut contract = contract_with_guardian();
+
+    as_user(&guardian(), 0);
+    contract.edit_pool(0, pool_from_json(staking_pool_json()));
+}
+
+#[test]
+#[should_panic(expected = "Caller not allowed")]
+fn removed_guardian_cannot_pause() {
+    let mut contract = contract_with_guardian();
+    contract.set_guardian(None);
+
+    as_user(&guardian(), 0);
+    contract.set_pool_paused(0, true);
+}
+
+#[test]
+fn pool_branding_round_trips() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_owner(0);
+    contract.set_pool_metadata(0, Some("data:image/svg+xml,<svg/>".to_string()), Some("Stake TKN".to_string()));
+
+    let pool = pool_json(&contract, 0);
+    assert_eq!(pool["icon"], "data:image/svg+xml,<svg/>");
+    assert_eq!(pool["description"], "Stake TKN");
+
+    let mut edited = staking_pool_json();
+    edited["description"] = "Stake TKN for 10%".into();
+    contract.edit_pool(0, pool_from_json(edited));
+
+    let pool = pool_json(&contract, 0);
+    assert!(pool["icon"].is_null());
+    assert_eq!(pool["description"], "Stake TKN for 10%");
+}
+
+#[test]
+fn reward_decimals_override_metadata() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.reward_decimals(0), 24);
+
+    as_owner(0);
+    contract.set_reward_decimals(0, 18);
+    assert_eq!(contract.reward_decimals(0), 18);
+    assert_eq!(pool_json(&contract, 0)["token_info"]["decimals"], 24);
+}
@@ -0,0 +1,310 @@
+mod setup;
+
+use setup::*;
+
+#[test]
+fn deposit_with_new_nonce_is_credited() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    transfer_call(&mut contract, &token(), &alice(), 100, "staking:0:nonce=1", START_TIME);
+    transfer_call(&mut contract, &token(), &alice(), 100, "staking:0:nonce=2", START_TIME);
+    // nonces are tracked per sender
+    transfer_call(&mut contract, &token(), &bob(), 100, "staking:0:nonce=1", START_TIME);
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 2);
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 1);
+}
+
+#[test]
+fn replayed_nonce_is_refunded() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    transfer_call(&mut contract, &token(), &alice(), 100, "staking:0:nonce=7", START_TIME);
+    let unused = transfer_call(&mut contract, &token(), &alice(), 100, "staking:0:nonce=7", START_TIME);
+
+    assert_eq!(unused, 100);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 1);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 100);
+}
+
+fn small_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["deposit_limiters"]["capacity"] = 1_000.into();
+    contract_with_pool(pool)
+}
+
+#[test]
+fn deposit_across_capacity_refunds_remainder() {
+    let mut contract = small_pool();
+    assert_eq!(transfer_call(&mut contract, &token(), &alice(), 700, "staking:0", START_TIME), 0);
+    let unused = transfer_call(&mut contract, &token(), &bob(), 500, "staking:0", START_TIME);
+
+    assert_eq!(unused, 200);
+    assert_eq!(as_u128(&position_json(&contract, 0, &bob(), 0)["amount"]), 300);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 1_000);
+}
+
+#[test]
+fn deposit_into_full_pool_is_refunded() {
+    let mut contract = small_pool();
+    transfer_call(&mut contract, &token(), &alice(), 1_000, "staking:0", START_TIME);
+    let unused = transfer_call(&mut contract, &token(), &bob(), 500, "staking:0", START_TIME);
+
+    assert_eq!(unused, 500);
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 1);
+}
+
+#[test]
+fn deposits_close_exactly_at_capacity() {
+    let mut contract = small_pool();
+    transfer_call(&mut contract, &token(), &alice(), 999, "staking:0", START_TIME);
+    assert_eq!(pool_json(&contract, 0)["deposits_closed"], false);
+    assert!(events("PoolFilled").is_empty());
+
+    transfer_call(&mut contract, &token(), &bob(), 1, "staking:0", START_TIME);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(pool["deposits_closed"], true);
+    assert_eq!(pool["paused"], false);
+    let filled = events("PoolFilled");
+    assert_eq!(filled.len(), 1);
+    assert_eq!(filled[0]["balance"], "1000");
+}
+
+#[test]
+fn filled_pool_still_pays_withdrawals_and_reopens() {
+    let mut contract = small_pool();
+    transfer_call(&mut contract, &token(), &alice(), 1_000, "staking:0", START_TIME);
+    assert_eq!(pool_json(&contract, 0)["deposits_closed"], true);
+
+    as_user(&alice(), START_TIME);
+    contract.withdraw(0, 0, 300);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(as_u128(&pool["funds"]["balance"]), 700);
+    assert_eq!(pool["deposits_closed"], false);
+
+    let unused = transfer_call(&mut contract, &token(), &carol(), 500, "staking:0", START_TIME);
+    assert_eq!(unused, 200);
+    assert_eq!(pool_json(&contract, 0)["deposits_closed"], true);
+
+    // past the lock a full pool pays out as usual
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 700);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 300);
+}
+
+#[test]
+#[should_panic(expected = "deposits disabled at this time: pid 0 takes deposits from 1000 to 10000")]
+fn late_deposit_names_the_window() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, END_TIME + 1);
+}
+
+#[test]
+#[should_panic(expected = "amount exceeds limit per transaction: pid 0 takes at most 1000000")]
+fn deposit_above_limit_names_the_limit() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_001, START_TIME);
+}
+
+fn pool_with_stake_cooldown() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["deposit_limiters"]["stake_cooldown_ms"] = 1_000.into();
+    contract_with_pool(pool)
+}
+
+#[test]
+fn stake_after_cooldown_is_credited() {
+    let mut contract = pool_with_stake_cooldown();
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &bob(), 0, 100, START_TIME + 1);
+    stake(&mut contract, &alice(), 0, 100, START_TIME + 1_000);
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 2);
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 1);
+}
+
+#[test]
+#[should_panic(expected = "stake cooldown active: pid 0 takes the next deposit at 2000")]
+fn stake_within_cooldown_is_rejected() {
+    let mut contract = pool_with_stake_cooldown();
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &alice(), 0, 100, START_TIME + 999);
+}
+
+#[test]
+#[should_panic(expected = "split deposit rejected")]
+fn split_deposit_into_cooldown_pool_twice_is_rejected_before_pull() {
+    let mut contract = pool_with_stake_cooldown();
+    as_user(&alice(), START_TIME);
+    contract.deposit_split(vec![(0, 100), (0, 200)]);
+}
+
+#[test]
+#[should_panic(expected = "amount should be greater than zero")]
+fn zero_amount_stake_is_rejected() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 0, START_TIME);
+}
+
+#[test]
+#[should_panic(expected = "amount should be greater than zero")]
+fn zero_amount_borrow_is_rejected() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000, 0);
+    borrow(&mut contract, &alice(), 0, 0, 0);
+}
+
+fn gated_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["gated"] = true.into();
+    let mut contract = contract_with_pool(pool);
+    as_owner(0);
+    contract.whitelist(0, alice(), true);
+    contract
+}
+
+#[test]
+fn gated_pool_credits_whitelisted_staker() {
+    let mut contract = gated_pool();
+    let unused = transfer_call(&mut contract, &token(), &alice(), 100, "staking:0", START_TIME);
+
+    assert_eq!(unused, 0);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 1);
+}
+
+#[test]
+fn gated_pool_refunds_other_stakers() {
+    let mut contract = gated_pool();
+    let unused = transfer_call(&mut contract, &token(), &bob(), 100, "staking:0", START_TIME);
+
+    assert_eq!(unused, 100);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 0);
+}
+
+fn two_staking_pools() -> staking_pool::Contract {
+    let mut contract = contract_with_pool(staking_pool_json());
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+    contract
+}
+
+#[test]
+fn split_deposit_pulls_total_and_credits_each_pool() {
+    let mut contract = two_staking_pools();
+    as_user(&alice(), START_TIME);
+    contract.deposit_split(vec![(0, 100), (1, 200)]);
+
+    let pulls: Vec<(near_sdk::AccountId, String, String)> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer_from")
+        .collect();
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].0, token());
+    assert!(pulls[0].2.contains("\"amount\":300"));
+
+    as_owner(START_TIME);
+    contract.deposit_split_callback(alice(), token(), vec![(0, 100), (1, 200)], Ok(()));
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 100);
+    assert_eq!(as_u128(&pool_json(&contract, 1)["funds"]["balance"]), 200);
+    assert_eq!(scheduled_calls().iter().filter(|call| call.1 == "ft_mint").count(), 2);
+    assert!(scheduled_calls().iter().all(|call| call.1 != "ft_transfer"));
+}
+
+#[test]
+#[should_panic(expected = "split deposit rejected")]
+fn split_deposit_is_rejected_when_one_pool_refuses() {
+    let mut contract = two_staking_pools();
+    as_owner(0);
+    contract.set_pool_paused(1, true);
+
+    as_user(&alice(), START_TIME);
+    contract.deposit_split(vec![(0, 100), (1, 200)]);
+}
+
+#[test]
+fn split_deposit_refunds_everything_when_a_pool_closed_meanwhile() {
+    let mut contract = two_staking_pools();
+    as_owner(START_TIME);
+    contract.set_pool_paused(1, true);
+
+    as_owner(START_TIME);
+    contract.deposit_split_callback(alice(), token(), vec![(0, 100), (1, 200)], Ok(()));
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 1)["funds"]["balance"]), 0);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"300\""));
+}
+
+#[test]
+#[should_panic(expected = "split deposit rejected")]
+fn split_deposit_over_capacity_is_rejected_before_pull() {
+    let mut contract = two_staking_pools();
+    for _ in 0..9 {
+        stake(&mut contract, &bob(), 1, 1_000_000, START_TIME);
+    }
+    stake(&mut contract, &bob(), 1, 999_900, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.deposit_split(vec![(0, 100), (1, 200)]);
+}
+
+#[test]
+fn split_deposit_refunds_everything_when_a_pool_filled_meanwhile() {
+    let mut contract = two_staking_pools();
+    for _ in 0..9 {
+        stake(&mut contract, &bob(), 1, 1_000_000, START_TIME);
+    }
+    stake(&mut contract, &bob(), 1, 999_900, START_TIME);
+
+    as_owner(START_TIME);
+    contract.deposit_split_callback(alice(), token(), vec![(0, 100), (1, 200)], Ok(()));
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 1)["funds"]["balance"]), 9_999_900);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"300\""));
+}
+
+fn minted_collateral() -> Vec<String> {
+    scheduled_calls()
+        .into_iter()
+        .filter(|call| call.0 == collateral() && call.1 == "ft_mint")
+        .map(|call| call.2)
+        .collect()
+}
+
+fn pool_with_collateral_decimals(decimals: u8, collateral_decimals: u8) -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["token_info"]["decimals"] = decimals.into();
+    pool["token_info"]["collateral_decimals"] = collateral_decimals.into();
+    contract_with_pool(pool)
+}
+
+#[test]
+fn collateral_is_minted_one_to_one_by_default() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_500, START_TIME);
+    assert!(minted_collateral()[0].ends_with("\"amount\":1500}"));
+}
+
+#[test]
+fn collateral_with_more_decimals_is_scaled_up() {
+    let mut contract = pool_with_collateral_decimals(6, 18);
+    stake(&mut contract, &alice(), 0, 1_500, START_TIME);
+    assert!(minted_collateral()[0].ends_with("\"amount\":1500000000000000}"));
+}
+
+#[test]
+fn collateral_with_fewer_decimals_is_rounded_down() {
+    let mut contract = pool_with_collateral_decimals(24, 21);
+    stake(&mut contract, &alice(), 0, 1_500, START_TIME);
+    assert!(minted_collateral()[0].ends_with("\"amount\":1}"));
+}
@@ -0,0 +1,706 @@
+mod setup;
+
+use near_sdk::json_types::U128;
+use setup::*;
+
+const BORROW_TIME: u64 = 5_000;
+
+fn funded_loan_pool() -> staking_pool::Contract {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn partial_repay_keeps_accruing_on_remaining_principal() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    // 10% apy at 50% utilisation
+    let interest = contract.calculate_interest(alice(), 0, 0, 200_000);
+    assert_eq!(interest, 10_000);
+    repay(&mut contract, &alice(), 0, 0, 200_000 + interest, 200_000, repay_time);
+
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["amount"]), 300_000);
+    assert_eq!(position["accrual_start"], BORROW_TIME);
+
+    // a year of interest on the remaining 300k at the new 30% utilisation
+    as_user(&alice(), repay_time);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 300_000), 9_000);
+
+    let pool = pool_json(&contract, 0);
+    assert_eq!(as_u128(&pool["funds"]["loaned_balance"]), 300_000);
+    assert_eq!(as_u128(&pool["funds"]["reward_reserve"]), interest);
+}
+
+fn loan_pool_with_min_interest() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["min_interest"] = 50.into();
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn same_block_repay_pays_minimum_interest() {
+    let mut contract = loan_pool_with_min_interest();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 500_000), 50);
+    repay(&mut contract, &alice(), 0, 0, 500_050, 500_000, BORROW_TIME);
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 50);
+}
+
+#[test]
+#[should_panic(expected = "amount less than repay amount + interest")]
+fn same_block_repay_without_interest_is_rejected() {
+    let mut contract = loan_pool_with_min_interest();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    repay(&mut contract, &alice(), 0, 0, 500_000, 500_000, BORROW_TIME);
+}
+
+#[test]
+fn repay_refunds_margin_above_interest_at_execution() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    // estimate half a year in, but the transfer only lands a year in
+    let estimate_time = BORROW_TIME + ONE_YEAR / 2;
+    as_user(&alice(), estimate_time);
+    let estimate = contract.calculate_interest(alice(), 0, 0, 500_000);
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    let interest = contract.calculate_interest(alice(), 0, 0, 500_000);
+    assert!(interest > estimate);
+
+    let refund = repay(&mut contract, &alice(), 0, 0, 500_000 + estimate * 3, 500_000, repay_time);
+    assert_eq!(refund, estimate * 3 - interest);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), interest);
+}
+
+#[test]
+fn exact_repay_leaves_nothing_to_refund() {
+    let mut contract = loan_pool_with_min_interest();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    assert_eq!(repay(&mut contract, &alice(), 0, 0, 500_050, 500_000, BORROW_TIME), 0);
+}
+
+#[test]
+#[should_panic(expected = "amount less than repay amount + interest")]
+fn repay_estimated_before_interest_ticked_up_is_rejected_without_margin() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR / 2);
+    let estimate = contract.calculate_interest(alice(), 0, 0, 500_000);
+    repay(&mut contract, &alice(), 0, 0, 500_000 + estimate, 500_000, BORROW_TIME + ONE_YEAR);
+}
+
+#[test]
+fn configured_borrower_borrows_within_limit() {
+    let mut contract = funded_loan_pool();
+    as_owner(BORROW_TIME);
+    contract.configure_borrower(0, alice(), true, 300_000);
+
+    as_user(&alice(), BORROW_TIME);
+    contract.borrow(0, 200_000);
+    contract.borrow(0, 100_000);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 2);
+}
+
+#[test]
+#[should_panic(expected = "borrow limit exceeded")]
+fn configured_borrower_cannot_borrow_over_limit() {
+    let mut contract = funded_loan_pool();
+    as_owner(BORROW_TIME);
+    contract.configure_borrower(0, alice(), true, 300_000);
+
+    as_user(&alice(), BORROW_TIME);
+    contract.borrow(0, 200_000);
+    contract.borrow(0, 100_001);
+}
+
+#[test]
+fn direct_repay_pulls_principal_plus_interest() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    contract.repay(0, 0, 200_000, 210_000);
+
+    let pulls: Vec<(near_sdk::AccountId, String, String)> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer_from")
+        .collect();
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].0, token());
+    assert!(pulls[0].2.contains("\"sender_id\":\"alice.near\""));
+    assert!(pulls[0].2.contains("\"amount\":210000"));
+
+    as_owner(repay_time);
+    contract.repay_callback(alice(), 0, 0, U128(210_000), U128(200_000), Ok(()));
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 300_000);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(as_u128(&pool["funds"]["loaned_balance"]), 300_000);
+    assert_eq!(as_u128(&pool["funds"]["reward_reserve"]), 10_000);
+}
+
+#[test]
+fn direct_repay_margin_covers_interest_accrued_before_callback() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    contract.repay(0, 0, 200_000, 230_000);
+    assert!(scheduled_calls().iter().any(|call| call.1 == "ft_transfer_from" && call.2.contains("\"amount\":230000")));
+
+    // the callback lands half a year later, with 15k of interest due by then
+    let callback_time = repay_time + ONE_YEAR / 2;
+    as_owner(callback_time);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 200_000), 15_000);
+    contract.repay_callback(alice(), 0, 0, U128(230_000), U128(200_000), Ok(()));
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 300_000);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 15_000);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"15000\""));
+}
+
+#[test]
+fn direct_repay_of_loan_closed_before_callback_is_refunded() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    contract.repay(0, 0, 200_000, 210_000);
+
+    // the whole loan is repaid through ft_transfer_call before the pull lands
+    repay(&mut contract, &alice(), 0, 0, 600_000, 500_000, repay_time);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+
+    as_owner(repay_time);
+    contract.repay_callback(alice(), 0, 0, U128(210_000), U128(200_000), Ok(()));
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["loaned_balance"]), 0);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"210000\""));
+}
+
+#[test]
+#[should_panic(expected = "max amount less than repay amount + interest")]
+fn direct_repay_rejects_max_amount_below_debt() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR);
+    contract.repay(0, 0, 200_000, 209_999);
+}
+
+#[test]
+fn direct_repay_refunds_when_interest_grew_meanwhile() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_owner(BORROW_TIME + 2 * ONE_YEAR);
+    contract.repay_callback(alice(), 0, 0, U128(210_000), U128(200_000), Ok(()));
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 500_000);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"210000\""));
+}
+
+#[test]
+fn direct_repay_refunds_interest_that_dropped_meanwhile() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    // 10k was pulled at 50% utilisation, bob's deposit halves the rate before the callback runs
+    stake(&mut contract, &bob(), 0, 1_000_000, BORROW_TIME);
+    as_owner(BORROW_TIME + ONE_YEAR);
+    contract.repay_callback(alice(), 0, 0, U128(210_000), U128(200_000), Ok(()));
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 300_000);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 5_000);
+    let refunds: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(refunds.len(), 1);
+    assert!(refunds[0].contains("\"amount\":\"5000\""));
+}
+
+#[test]
+#[should_panic(expected = "repayment transfer failed")]
+fn direct_repay_requires_the_transfer() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_owner(BORROW_TIME + ONE_YEAR);
+    contract.repay_callback(alice(), 0, 0, U128(210_000), U128(200_000), Err(near_sdk::PromiseError::Failed));
+}
+
+#[test]
+fn full_repay_amount_is_principal_plus_interest() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let repay_time = BORROW_TIME + ONE_YEAR;
+    as_user(&alice(), repay_time);
+    let interest = contract.calculate_interest(alice(), 0, 0, 500_000);
+    assert_eq!(interest, 25_000);
+    assert_eq!(contract.full_repay_amount(0, alice(), 0).0, 525_000);
+
+    repay(&mut contract, &alice(), 0, 0, 525_000, 500_000, repay_time);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+}
+
+#[test]
+fn borrower_count_follows_outstanding_loans() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME);
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME);
+    borrow(&mut contract, &carol(), 0, 100_000, BORROW_TIME);
+    assert_eq!(contract.borrower_count(0), 2);
+    // bob only staked
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 3);
+
+    repay(&mut contract, &alice(), 0, 0, 100_000, 100_000, BORROW_TIME);
+    assert_eq!(contract.borrower_count(0), 2);
+    repay(&mut contract, &alice(), 0, 0, 100_000, 100_000, BORROW_TIME);
+    assert_eq!(contract.borrower_count(0), 1);
+    repay(&mut contract, &carol(), 0, 0, 100_000, 100_000, BORROW_TIME);
+    assert_eq!(contract.borrower_count(0), 0);
+}
+
+fn loan_pool_with_reserve_floor() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["deposit_limiters"]["min_reserve_bps"] = 3_000.into();
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn borrow_down_to_reserve_floor_is_allowed() {
+    let mut contract = loan_pool_with_reserve_floor();
+    borrow(&mut contract, &alice(), 0, 700_000, BORROW_TIME);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["loaned_balance"]), 700_000);
+}
+
+#[test]
+#[should_panic(expected = "reserve below minimum")]
+fn borrow_below_reserve_floor_is_rejected() {
+    let mut contract = loan_pool_with_reserve_floor();
+    borrow(&mut contract, &alice(), 0, 600_000, BORROW_TIME);
+    borrow(&mut contract, &alice(), 0, 100_001, BORROW_TIME);
+}
+
+#[test]
+#[should_panic(expected = "utilisation maxed out: pid 0 would be at 90%, max 80%")]
+fn borrow_above_max_utilisation_names_the_pool() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 900_000, BORROW_TIME);
+}
+
+#[test]
+#[should_panic(expected = "Pool Paused: pid 1")]
+fn borrow_from_paused_pool_names_the_pool() {
+    let mut contract = funded_loan_pool();
+    as_owner(0);
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+    stake(&mut contract, &bob(), 1, 1_000_000, 0);
+    as_owner(0);
+    contract.set_pool_paused(1, true);
+
+    borrow(&mut contract, &alice(), 1, 100_000, BORROW_TIME);
+}
+
+#[test]
+fn open_loans_are_listed_in_pages() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &carol(), 0, 50_000, BORROW_TIME);
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME + 1);
+    borrow(&mut contract, &alice(), 0, 200_000, BORROW_TIME + 2);
+    repay(&mut contract, &alice(), 0, 0, 100_000, 40_000, BORROW_TIME + 3);
+
+    let loans = contract.open_loans(0, 0, 10);
+    assert_eq!(
+        loans,
+        vec![
+            (alice(), 0, U128(60_000), BORROW_TIME + 1),
+            (alice(), 1, U128(200_000), BORROW_TIME + 2),
+            (carol(), 0, U128(50_000), BORROW_TIME),
+        ]
+    );
+    assert_eq!(contract.open_loans(0, 1, 2), vec![(alice(), 1, U128(200_000), BORROW_TIME + 2)]);
+    assert!(contract.open_loans(0, 3, 5).is_empty());
+}
+
+fn loan_pool_with_withdraw_buffer() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["deposit_limiters"]["withdraw_buffer_bps"] = 2_000.into();
+    pool["deposit_limiters"]["max_utilisation"] = 95.into();
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn depositor_exits_buffer_at_max_utilisation() {
+    let mut contract = loan_pool_with_withdraw_buffer();
+    borrow(&mut contract, &alice(), 0, 790_000, BORROW_TIME);
+
+    as_user(&bob(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 200_000);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(as_u128(&pool["funds"]["balance"]), 800_000);
+    assert_eq!(as_u128(&pool["funds"]["loaned_balance"]), 790_000);
+}
+
+#[test]
+#[should_panic(expected = "utilisation maxed out")]
+fn withdraw_beyond_buffer_follows_utilisation_cap() {
+    let mut contract = loan_pool_with_withdraw_buffer();
+    borrow(&mut contract, &alice(), 0, 790_000, BORROW_TIME);
+
+    as_user(&bob(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 200_001);
+}
+
+#[test]
+#[should_panic(expected = "withdraw buffer reserved: pid 0 would keep 1999 bps, buffer 2000 bps")]
+fn borrow_into_withdraw_buffer_is_rejected() {
+    let mut contract = loan_pool_with_withdraw_buffer();
+    borrow(&mut contract, &alice(), 0, 600_000, BORROW_TIME);
+    borrow(&mut contract, &alice(), 0, 200_001, BORROW_TIME);
+}
+
+#[test]
+fn loan_age_grows_with_time() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME);
+    assert_eq!(contract.loan_age(0, alice(), 0), 0);
+    as_user(&alice(), BORROW_TIME + ONE_YEAR);
+    assert_eq!(contract.loan_age(0, alice(), 0), ONE_YEAR);
+}
+
+#[test]
+fn partial_repay_keeps_loan_age() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+    repay(&mut contract, &alice(), 0, 0, 300_000, 200_000, BORROW_TIME + ONE_YEAR);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR * 2);
+    assert_eq!(contract.loan_age(0, alice(), 0), ONE_YEAR * 2);
+}
+
+#[test]
+#[should_panic(expected = "not borrwed")]
+fn loan_age_rejects_stakes() {
+    let contract = funded_loan_pool();
+    contract.loan_age(0, bob(), 0);
+}
+
+#[test]
+fn remaining_principal_after_partial_repayments() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+    as_user(&alice(), BORROW_TIME);
+    assert_eq!(contract.remaining_principal(0, alice(), 0), U128(500_000));
+
+    repay(&mut contract, &alice(), 0, 0, 300_000, 200_000, BORROW_TIME + ONE_YEAR);
+    assert_eq!(contract.remaining_principal(0, alice(), 0), U128(300_000));
+    repay(&mut contract, &alice(), 0, 0, 200_000, 100_000, BORROW_TIME + ONE_YEAR * 2);
+    assert_eq!(contract.remaining_principal(0, alice(), 0), U128(200_000));
+}
+
+#[test]
+#[should_panic(expected = "not borrwed")]
+fn remaining_principal_rejects_stakes() {
+    let contract = funded_loan_pool();
+    contract.remaining_principal(0, bob(), 0);
+}
+
+#[test]
+fn failed_borrow_payout_drops_the_loan() {
+    let mut pool = loan_pool_json();
+    pool["origination_fee_bps"] = near_sdk::serde_json::json!(200);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+    assert!(scheduled_calls().iter().any(|call| call.1 == "resolve_borrow"));
+
+    as_owner(BORROW_TIME + 1);
+    contract.resolve_borrow(alice(), 0, U128(500_000), U128(10_000), BORROW_TIME, Err(near_sdk::PromiseError::Failed));
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(contract.borrower_count(0), 0);
+    let pool = pool_json(&contract, 0);
+    assert_eq!(as_u128(&pool["funds"]["loaned_balance"]), 0);
+    assert_eq!(as_u128(&pool["funds"]["fees_collected"]), 0);
+    assert_eq!(as_u128(&pool["unique_users"]), 1);
+    assert!(contract.check_invariants(0).is_empty());
+}
+
+#[test]
+fn successful_borrow_payout_keeps_the_loan() {
+    let mut contract = funded_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_owner(BORROW_TIME + 1);
+    contract.resolve_borrow(alice(), 0, U128(500_000), U128(0), BORROW_TIME, Ok(()));
+    assert_eq!(contract.remaining_principal(0, alice(), 0), U128(500_000));
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["loaned_balance"]), 500_000);
+}
+
+#[test]
+fn origination_fee_is_kept_from_the_payout() {
+    let mut pool = loan_pool_json();
+    pool["origination_fee_bps"] = near_sdk::serde_json::json!(200);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    let payouts: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert!(payouts[0].contains("\"amount\":\"490000\""));
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 500_000);
+    let funds = &pool_json(&contract, 0)["funds"];
+    assert_eq!(as_u128(&funds["loaned_balance"]), 500_000);
+    assert_eq!(as_u128(&funds["fees_collected"]), 10_000);
+}
+
+fn kinked_loan_pool() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["interest_model"] = near_sdk::serde_json::json!({
+        "Kinked": { "kink_bps": 5_000, "base_bps": 200, "slope1_bps": 400, "slope2_bps": 6_000 }
+    });
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn kinked_rate_below_the_kink() {
+    let mut contract = kinked_loan_pool();
+    borrow(&mut contract, &alice(), 0, 400_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR);
+    // 2% base + 4% * 40/50 = 5.2%
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 400_000), 20_800);
+}
+
+#[test]
+fn kinked_rate_above_the_kink() {
+    let mut contract = kinked_loan_pool();
+    borrow(&mut contract, &alice(), 0, 700_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR);
+    // 2% base + 4% + 60% * 20/50 = 30%
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 700_000), 210_000);
+}
+
+#[test]
+#[should_panic(expected = "kink must be within (0, 10000) bps")]
+fn kink_at_full_utilisation_is_rejected() {
+    let mut pool = loan_pool_json();
+    pool["interest_model"] = near_sdk::serde_json::json!({
+        "Kinked": { "kink_bps": 10_000, "base_bps": 0, "slope1_bps": 0, "slope2_bps": 0 }
+    });
+    contract_with_pool(pool);
+}
+
+#[test]
+fn health_factor_of_a_covered_loan() {
+    let mut contract = funded_loan_pool();
+    stake(&mut contract, &alice(), 0, 300_000, 0);
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME);
+    assert_eq!(contract.health_factor(0, alice(), 1).0, 300);
+}
+
+#[test]
+fn health_factor_falls_as_interest_accrues() {
+    let mut contract = funded_loan_pool();
+    stake(&mut contract, &alice(), 0, 100_000, 0);
+    borrow(&mut contract, &alice(), 0, 100_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + 10 * ONE_YEAR);
+    assert!(contract.health_factor(0, alice(), 1).0 < 100);
+}
+
+fn usdc() -> near_sdk::AccountId {
+    "usdc.near".parse().unwrap()
+}
+
+fn eth() -> near_sdk::AccountId {
+    "eth.near".parse().unwrap()
+}
+
+/// Loan pool taking usdc at an 80% and eth at a 50% collateral factor, alice posted both
+fn secured_loan_pool() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["token_info"]["accepted_collateral"] = near_sdk::serde_json::json!([[usdc(), 8_000], [eth(), 5_000]]);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    transfer_call(&mut contract, &usdc(), &alice(), 100_000, "collateral:0", 0);
+    transfer_call(&mut contract, &eth(), &alice(), 200_000, "collateral:0", 0);
+    contract
+}
+
+#[test]
+fn collateral_in_two_assets_adds_up() {
+    let mut contract = secured_loan_pool();
+    assert_eq!(contract.posted_collateral(0, alice()), vec![(eth(), U128(200_000)), (usdc(), U128(100_000))]);
+    assert_eq!(contract.borrow_capacity(0, alice()).0, 180_000);
+
+    borrow(&mut contract, &alice(), 0, 150_000, BORROW_TIME);
+    as_user(&alice(), BORROW_TIME);
+    assert_eq!(contract.health_factor(0, alice(), 0).0, 120);
+}
+
+#[test]
+#[should_panic(expected = "insufficient collateral")]
+fn borrowing_past_collateral_is_rejected() {
+    let mut contract = secured_loan_pool();
+    borrow(&mut contract, &alice(), 0, 150_000, BORROW_TIME);
+    borrow(&mut contract, &alice(), 0, 40_000, BORROW_TIME);
+}
+
+#[test]
+fn unused_collateral_can_be_withdrawn() {
+    let mut contract = secured_loan_pool();
+    borrow(&mut contract, &alice(), 0, 150_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME);
+    contract.withdraw_collateral(0, eth(), 20_000);
+    assert_eq!(contract.borrow_capacity(0, alice()).0, 170_000);
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, eth());
+    assert!(calls[0].2.contains("\"amount\":\"20000\""));
+}
+
+#[test]
+#[should_panic(expected = "insufficient collateral")]
+fn collateral_backing_a_loan_stays_posted() {
+    let mut contract = secured_loan_pool();
+    borrow(&mut contract, &alice(), 0, 150_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME);
+    contract.withdraw_collateral(0, eth(), 100_000);
+}
+
+#[test]
+#[should_panic(expected = "collateral not accepted")]
+fn unlisted_collateral_is_rejected() {
+    let mut contract = secured_loan_pool();
+    transfer_call(&mut contract, &token(), &alice(), 100_000, "collateral:0", 0);
+}
+
+#[test]
+fn approved_controller_borrows_for_borrower() {
+    let mut contract = funded_loan_pool();
+    as_owner(BORROW_TIME);
+    contract.whitelist(0, alice(), true);
+    as_user(&alice(), BORROW_TIME);
+    contract.set_borrow_operator(carol(), true);
+    assert!(contract.is_borrow_operator(alice(), carol()));
+
+    as_user(&carol(), BORROW_TIME);
+    contract.borrow_for(alice(), 0, 100_000);
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 100_000);
+    assert_eq!(contract.borrower_count(0), 1);
+    let payouts: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert!(payouts[0].contains("\"receiver_id\":\"alice.near\""));
+}
+
+#[test]
+#[should_panic(expected = "not an approved borrow operator")]
+fn withdraw_operator_cannot_borrow() {
+    let mut contract = funded_loan_pool();
+    as_owner(BORROW_TIME);
+    contract.whitelist(0, alice(), true);
+    as_user(&alice(), BORROW_TIME);
+    contract.set_operator(carol(), true);
+
+    as_user(&carol(), BORROW_TIME);
+    contract.borrow_for(alice(), 0, 100_000);
+}
+
+#[test]
+#[should_panic(expected = "not an approved borrow operator")]
+fn unapproved_controller_cannot_borrow() {
+    let mut contract = funded_loan_pool();
+    as_owner(BORROW_TIME);
+    contract.whitelist(0, alice(), true);
+
+    as_user(&carol(), BORROW_TIME);
+    contract.borrow_for(alice(), 0, 100_000);
+}
+
+fn capped_loan_pool() -> staking_pool::Contract {
+    let mut pool = loan_pool_json();
+    pool["max_interest_bps"] = near_sdk::serde_json::json!(1_500);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    contract
+}
+
+#[test]
+fn interest_below_the_cap_is_unchanged() {
+    let mut contract = capped_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 500_000), 25_000);
+}
+
+#[test]
+fn long_held_loan_interest_is_capped() {
+    let mut contract = capped_loan_pool();
+    borrow(&mut contract, &alice(), 0, 500_000, BORROW_TIME);
+
+    as_user(&alice(), BORROW_TIME + 10 * ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 500_000), 75_000);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 100_000), 15_000);
+}
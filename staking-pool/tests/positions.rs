@@ -0,0 +1,496 @@
+mod setup;
+
+use near_sdk::json_types::U128;
+use setup::*;
+
+#[test]
+fn transfer_position_moves_stake_between_accounts() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &alice(), 0, 200, START_TIME);
+    stake(&mut contract, &alice(), 0, 300, START_TIME);
+    stake(&mut contract, &bob(), 0, 50, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+
+    // alice's last position took over index 0
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 2);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 300);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 1)["amount"]), 200);
+
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 2);
+    assert_eq!(as_u128(&position_json(&contract, 0, &bob(), 1)["amount"]), 100);
+
+    let methods: Vec<String> = scheduled_calls().into_iter().map(|call| call.1).collect();
+    assert_eq!(methods, vec!["ft_burn", "resolve_transfer_position"]);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 2);
+
+    as_owner(START_TIME);
+    contract.resolve_transfer_position(0, alice(), bob(), U128(100), START_TIME, Ok(()));
+    let mints: Vec<(near_sdk::AccountId, String, String)> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_mint")
+        .collect();
+    assert_eq!(mints.len(), 1);
+    assert_eq!(mints[0].0, collateral());
+    assert!(mints[0].2.contains("\"receiver_id\":\"bob.near\""));
+}
+
+#[test]
+fn failed_collateral_burn_returns_the_position() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &bob(), 0, 50, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+    as_owner(START_TIME);
+    contract.resolve_transfer_position(0, alice(), bob(), U128(100), START_TIME, Err(near_sdk::PromiseError::Failed));
+
+    assert!(scheduled_calls().iter().all(|call| call.1 != "ft_mint"));
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 1);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 100);
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 1);
+    assert_eq!(as_u128(&position_json(&contract, 0, &bob(), 0)["amount"]), 50);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 2);
+}
+
+#[test]
+#[should_panic(expected = "Pool Paused: pid 0")]
+fn paused_pool_positions_cannot_be_transferred() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    as_owner(START_TIME);
+    contract.set_pool_paused(0, true);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+}
+
+#[test]
+#[should_panic(expected = "amount exceeds limit per transaction: pid 0 takes at most 1000000")]
+fn transfer_above_limit_per_user_is_rejected() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    as_owner(START_TIME);
+    contract.admin_adjust_stake(0, alice(), 0, 1_500_000);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+}
+
+#[test]
+fn transfer_last_position_updates_unique_users() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(contract.total_stakes_of_user(0, bob()), 1);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 1);
+}
+
+#[test]
+#[should_panic(expected = "receiver not whitelisted: pid 0 is gated")]
+fn gated_position_cannot_go_to_account_off_the_whitelist() {
+    let mut pool = staking_pool_json();
+    pool["gated"] = true.into();
+    let mut contract = contract_with_pool(pool);
+    as_owner(0);
+    contract.whitelist(0, alice(), true);
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.transfer_position(0, 0, bob());
+}
+
+#[test]
+fn approved_operator_withdraws_to_owner() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    fund_rewards(&mut contract, 0, 100);
+
+    as_user(&alice(), START_TIME);
+    contract.set_operator(bob(), true);
+    assert!(contract.is_operator(alice(), bob()));
+
+    as_user(&bob(), END_TIME + ONE_YEAR);
+    contract.withdraw_for(alice(), 0, 0, 100);
+
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    let transfers: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(transfers.len(), 1);
+    assert!(transfers[0].contains("alice.near"));
+}
+
+#[test]
+#[should_panic(expected = "not an approved operator")]
+fn unapproved_operator_cannot_withdraw() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.set_operator(bob(), false);
+
+    as_user(&bob(), END_TIME + ONE_YEAR);
+    contract.withdraw_for(alice(), 0, 0, 100);
+}
+
+#[test]
+fn emergency_withdraw_updates_pool_balance() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.emergency_withdraw(0, 0, 40);
+
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 0)["amount"]), 60);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 60);
+}
+
+#[test]
+#[should_panic(expected = "amount greater than transaction")]
+fn emergency_withdraw_rejects_over_withdraw() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.emergency_withdraw(0, 0, 101);
+}
+
+#[test]
+#[should_panic(expected = "insufficient pool balance")]
+fn emergency_withdraw_rejects_lent_out_funds() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000, 0);
+    borrow(&mut contract, &alice(), 0, 500, 0);
+
+    as_user(&bob(), 0);
+    contract.emergency_withdraw(0, 0, 600);
+}
+
+#[test]
+fn partial_withdrawals_accumulate_in_withdrawn() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.emergency_withdraw(0, 0, 10);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 30);
+
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["amount"]), 60);
+    assert_eq!(as_u128(&position["withdrawn"]), 40);
+}
+
+#[test]
+fn withdraw_fee_is_kept_by_the_pool() {
+    let mut pool = staking_pool_json();
+    pool["withdraw_fee_bps"] = near_sdk::serde_json::json!(100);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 10_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 10_000);
+
+    let transfers: Vec<String> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_transfer")
+        .map(|call| call.2)
+        .collect();
+    assert_eq!(transfers.len(), 1);
+    assert!(transfers[0].contains("\"amount\":\"9900\""));
+
+    let funds = &pool_json(&contract, 0)["funds"];
+    assert_eq!(as_u128(&funds["balance"]), 0);
+    assert_eq!(as_u128(&funds["fees_collected"]), 100);
+}
+
+fn circuit_breaker_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["outflow_limit"] = near_sdk::serde_json::json!(1_000);
+    pool["outflow_window_ms"] = near_sdk::serde_json::json!(3_600_000);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    contract
+}
+
+#[test]
+fn outflow_below_limit_keeps_pool_open() {
+    let mut contract = circuit_breaker_pool();
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 600);
+    // the next withdrawal falls into a new window
+    as_user(&alice(), END_TIME + ONE_YEAR + 3_600_000);
+    contract.withdraw(0, 0, 600);
+
+    assert_eq!(pool_json(&contract, 0)["paused"], false);
+    assert!(events("CircuitBreakerTripped").is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Pool Paused")]
+fn outflow_above_limit_pauses_pool() {
+    let mut contract = circuit_breaker_pool();
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 600);
+    as_user(&alice(), END_TIME + ONE_YEAR + 1_000);
+    contract.withdraw(0, 0, 600);
+
+    assert_eq!(pool_json(&contract, 0)["paused"], true);
+    let tripped = events("CircuitBreakerTripped");
+    assert_eq!(tripped.len(), 1);
+    assert_eq!(as_u128(&tripped[0]["outflow"]), 1_200);
+
+    contract.withdraw(0, 0, 1);
+}
+
+fn collateral_calls() -> Vec<(String, String)> {
+    scheduled_calls()
+        .into_iter()
+        .filter(|call| call.0 == collateral())
+        .map(|call| (call.1, call.2))
+        .collect()
+}
+
+#[test]
+fn withdraw_burns_collateral_by_default() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 100);
+
+    let calls = collateral_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0, "ft_burn");
+}
+
+#[test]
+fn withdraw_sends_collateral_to_sink() {
+    let treasury: near_sdk::AccountId = "treasury.near".parse().unwrap();
+    let mut pool = staking_pool_json();
+    pool["collateral_burn"] = near_sdk::serde_json::json!(false);
+    pool["collateral_sink"] = near_sdk::serde_json::json!(treasury);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 100);
+
+    let calls = collateral_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0, "ft_transfer_from");
+    assert!(calls[0].1.contains("\"sender_id\":\"alice.near\""));
+    assert!(calls[0].1.contains("\"receiver_id\":\"treasury.near\""));
+}
+
+#[test]
+#[should_panic(expected = "collateral sink required")]
+fn collateral_transfer_mode_needs_a_sink() {
+    let mut pool = staking_pool_json();
+    pool["collateral_burn"] = near_sdk::serde_json::json!(false);
+    contract_with_pool(pool);
+}
+
+#[test]
+fn split_position_keeps_principal_and_rewards() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 4);
+    contract.compound_rewards(0, 0);
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    let reward_before = contract.calculate_interest(alice(), 0, 0, 1_025_000);
+    contract.split_position(0, 0, 300_000);
+
+    let original = position_json(&contract, 0, &alice(), 0);
+    let split = position_json(&contract, 0, &alice(), 1);
+    assert_eq!(as_u128(&original["amount"]) + as_u128(&split["amount"]), 1_025_000);
+    assert_eq!(as_u128(&split["amount"]), 300_000);
+    assert_eq!(original["time"], split["time"]);
+    assert_eq!(as_u128(&original["paid_out"]) + as_u128(&split["paid_out"]), 25_000);
+
+    let reward_after = contract.calculate_interest(alice(), 0, 0, 725_000)
+        + contract.calculate_interest(alice(), 0, 1, 300_000);
+    assert!(reward_before - reward_after <= 1);
+
+    // each half keeps earning on its own principal only
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    let later = contract.calculate_interest(alice(), 0, 0, 725_000)
+        + contract.calculate_interest(alice(), 0, 1, 300_000);
+    assert!(later - (reward_before + 51_250) <= 1);
+}
+
+#[test]
+#[should_panic(expected = "split amount out of range")]
+fn split_position_rejects_whole_amount() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.split_position(0, 0, 100);
+}
+
+#[test]
+fn collateral_balance_queries_the_collateral_token() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    contract.collateral_balance(0, alice());
+    let queries: Vec<(near_sdk::AccountId, String, String)> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_balance_of")
+        .collect();
+    assert_eq!(queries.len(), 1);
+    assert_eq!(queries[0].0, collateral());
+    assert!(queries[0].2.contains("alice.near"));
+}
+
+#[test]
+fn matching_collateral_logs_nothing() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_owner(START_TIME);
+    assert_eq!(contract.collateral_balance_callback(0, alice(), near_sdk::json_types::U128(100)).0, 100);
+    assert!(events("CollateralMismatch").is_empty());
+}
+
+#[test]
+fn drifted_collateral_is_reported() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+
+    as_owner(START_TIME);
+    contract.collateral_balance_callback(0, alice(), near_sdk::json_types::U128(60));
+    let mismatches = events("CollateralMismatch");
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(as_u128(&mismatches[0]["staked"]), 100);
+    assert_eq!(as_u128(&mismatches[0]["collateral"]), 60);
+}
+
+fn withdrawn_with_reward() -> staking_pool::Contract {
+    let mut contract = contract_with_pool(staking_pool_json());
+    fund_rewards(&mut contract, 0, 5_000);
+    stake(&mut contract, &alice(), 0, 10_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 10_000);
+    contract
+}
+
+#[test]
+fn withdraw_pays_principal_and_reward_together() {
+    withdrawn_with_reward();
+
+    let calls = scheduled_calls();
+    let transfers: Vec<&(near_sdk::AccountId, String, String)> = calls.iter().filter(|call| call.1 == "ft_transfer").collect();
+    assert_eq!(transfers.len(), 1);
+    assert!(transfers[0].2.contains("\"amount\":\"11000\""));
+    assert!(calls.iter().any(|call| call.1 == "withdraw_callback"));
+}
+
+#[test]
+fn failed_withdraw_transfer_restores_the_position() {
+    let mut contract = withdrawn_with_reward();
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 4_000);
+
+    as_owner(END_TIME + ONE_YEAR);
+    contract.withdraw_callback(alice(), 0, U128(10_000), U128(0), U128(1_000), Err(near_sdk::PromiseError::Failed));
+
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["amount"]), 10_000);
+    assert_eq!(as_u128(&position["unpaid_reward"]), 1_000);
+    let funds = &pool_json(&contract, 0)["funds"];
+    assert_eq!(as_u128(&funds["balance"]), 10_000);
+    assert_eq!(as_u128(&funds["reward_reserve"]), 5_000);
+    assert_eq!(as_u128(&funds["total_rewards_paid"]), 0);
+    assert!(contract.check_invariants(0).is_empty());
+    assert_eq!(collateral_calls()[0].0, "ft_mint");
+
+    // the restored position can be withdrawn again with its reward
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 10_000);
+    assert!(scheduled_calls().iter().any(|call| call.1 == "ft_transfer" && call.2.contains("\"amount\":\"11000\"")));
+}
+
+#[test]
+#[should_panic(expected = "high utilisation: pid 0 has 1500 of 2000 lent out")]
+fn staking_withdraw_leaves_lent_funds() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &bob(), 0, 1_000, START_TIME);
+    // a hybrid pool that has lent out part of its stake
+    contract.set_funds_for_testing(0, 2_000, 1_500);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 1_000);
+}
+
+#[test]
+fn time_weighted_balance_reflects_holding_time() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &bob(), 0, 1_000, START_TIME + 4_000);
+
+    as_user(&alice(), END_TIME);
+    assert_eq!(contract.time_weighted_balance(0, alice(), 0).0, 1_000 * 9_000);
+    assert_eq!(contract.time_weighted_balance(0, bob(), 0).0, 1_000 * 5_000);
+}
+
+#[test]
+fn time_weighted_balance_follows_partial_withdrawals() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+
+    as_user(&alice(), START_TIME + 4_000);
+    contract.emergency_withdraw(0, 0, 500);
+
+    as_user(&alice(), START_TIME + 9_000);
+    assert_eq!(contract.time_weighted_balance(0, alice(), 0).0, 1_000 * 4_000 + 500 * 5_000);
+}
+
+#[test]
+fn staking_position_unlocks_after_duration() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+
+    as_user(&alice(), START_TIME);
+    assert_eq!(contract.unlock_time(0, alice(), 0), END_TIME + ONE_YEAR);
+    as_user(&alice(), END_TIME + 2 * ONE_YEAR);
+    assert_eq!(contract.unlock_time(0, alice(), 0), END_TIME + ONE_YEAR);
+}
+
+#[test]
+fn loan_pool_deposit_unlocks_after_deposit_window() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+
+    as_user(&bob(), 0);
+    assert_eq!(contract.unlock_time(0, bob(), 0), END_TIME);
+    as_user(&bob(), END_TIME + 5);
+    assert_eq!(contract.unlock_time(0, bob(), 0), END_TIME);
+}
+
+#[test]
+#[should_panic(expected = "not staked")]
+fn unlock_time_rejects_loans() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 100_000, 0);
+
+    contract.unlock_time(0, alice(), 0);
+}
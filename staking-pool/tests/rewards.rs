@@ -0,0 +1,673 @@
+mod setup;
+
+use near_sdk::serde_json::{self, json};
+use setup::*;
+use staking_pool::{PoolType, QUARTER_DAY};
+
+fn quarterly_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    contract_with_pool(pool)
+}
+
+#[test]
+fn reward_shortfall_is_recorded_and_claimable_later() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 10);
+
+    let claim_time = END_TIME + QUARTER_DAY;
+    as_user(&alice(), claim_time);
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.claim_quarterly_payout(0, 0);
+
+    let shortfalls = events("RewardShortfall");
+    assert_eq!(shortfalls.len(), 1);
+    assert_eq!(as_u128(&shortfalls[0]["requested"]), reward);
+    assert_eq!(as_u128(&shortfalls[0]["paid"]), 10);
+
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["paid_out"]), 10);
+    assert_eq!(as_u128(&position["unpaid_reward"]), reward - 10);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 0);
+
+    fund_rewards(&mut contract, 0, reward);
+    as_user(&alice(), claim_time);
+    contract.claim_quarterly_payout(0, 0);
+
+    assert!(events("RewardShortfall").is_empty());
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["paid_out"]), reward);
+    assert_eq!(as_u128(&position["unpaid_reward"]), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 10);
+}
+
+#[test]
+fn full_withdraw_keeps_shortfall_claimable() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 10);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.withdraw(0, 0, 1_000_000);
+
+    // the principal is gone but the position stays for what the reserve could not pay
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["amount"]), 0);
+    assert_eq!(as_u128(&position["unpaid_reward"]), reward - 10);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 1);
+
+    fund_rewards(&mut contract, 0, reward);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.claim_quarterly_payout(0, 0);
+
+    assert_eq!(contract.rewards_paid(0).0, reward);
+    assert_eq!(contract.total_stakes_of_user(0, alice()), 0);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["unique_users"]), 0);
+}
+
+#[test]
+fn rewards_paid_grows_by_each_claim() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    stake(&mut contract, &bob(), 0, 500_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+    assert_eq!(contract.rewards_paid(0).0, 0);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    let alice_reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.claim_quarterly_payout(0, 0);
+    assert_eq!(contract.rewards_paid(0).0, alice_reward);
+
+    as_user(&bob(), END_TIME + 2 * QUARTER_DAY);
+    let bob_reward = contract.calculate_interest(bob(), 0, 0, 500_000);
+    contract.claim_quarterly_payout(0, 0);
+    assert_eq!(contract.rewards_paid(0).0, alice_reward + bob_reward);
+}
+
+#[test]
+fn rewards_finalize_after_duration() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR - 1);
+    assert!(!contract.rewards_finalized(0, alice(), 0));
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert!(contract.rewards_finalized(0, alice(), 0));
+    let final_reward = contract.calculate_interest(alice(), 0, 0, 1_000);
+    assert_eq!(final_reward, 100);
+
+    as_user(&alice(), END_TIME + 2 * ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000), final_reward);
+}
+
+#[test]
+fn rewards_do_not_grow_while_paused() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    let quarter = ONE_YEAR / 4;
+
+    as_owner(END_TIME + quarter);
+    let before_pause = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.set_rewards_paused(0, true);
+
+    as_user(&alice(), END_TIME + 2 * quarter);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), before_pause);
+
+    as_owner(END_TIME + 2 * quarter);
+    contract.set_rewards_paused(0, false);
+
+    // only two of the three quarters count
+    as_user(&alice(), END_TIME + 3 * quarter);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), 2 * before_pause);
+}
+
+#[test]
+fn loan_interest_ignores_pauses_before_the_borrow() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+
+    as_owner(1_000);
+    contract.set_rewards_paused(0, true);
+    as_owner(ONE_YEAR);
+    contract.set_rewards_paused(0, false);
+
+    borrow(&mut contract, &alice(), 0, 500_000, ONE_YEAR);
+    as_user(&alice(), 2 * ONE_YEAR);
+    // 10% apy at 50% utilisation
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 500_000), 25_000);
+}
+
+#[test]
+fn compounding_mints_collateral_for_the_new_principal() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    let compounded = contract.compound_rewards(0, 0).0;
+    assert_eq!(compounded, 50_000);
+
+    let mints: Vec<(near_sdk::AccountId, String, String)> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_mint")
+        .collect();
+    assert_eq!(mints.len(), 1);
+    assert_eq!(mints[0].0, collateral());
+    assert!(mints[0].2.contains("\"amount\":50000"));
+
+    let position = position_json(&contract, 0, &alice(), 0);
+    assert_eq!(as_u128(&position["amount"]), 1_050_000);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["balance"]), 1_050_000);
+
+    // the compounded part only earns from now on
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    let claimable = contract.calculate_interest(alice(), 0, 0, 1_050_000) - as_u128(&position["paid_out"]);
+    assert_eq!(claimable, 52_500);
+}
+
+#[test]
+#[should_panic(expected = "Rewards Paused")]
+fn compounding_is_rejected_while_rewards_paused() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+    as_owner(END_TIME);
+    contract.set_rewards_paused(0, true);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    contract.compound_rewards(0, 0);
+}
+
+#[test]
+#[should_panic(expected = "claim cooldown active")]
+fn compounding_within_claim_cooldown_is_rejected() {
+    let mut contract = cooldown_pool();
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+    as_user(&alice(), END_TIME + 2 * QUARTER_DAY - 1);
+    contract.compound_rewards(0, 0);
+}
+
+#[test]
+fn position_yield_splits_paid_and_claimable() {
+    let mut pool = staking_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 4);
+    contract.claim_quarterly_payout(0, 0);
+    let (paid, claimable) = contract.position_yield(0, alice(), 0);
+    assert_eq!((paid.0, claimable.0), (25_000, 0));
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    let (paid, claimable) = contract.position_yield(0, alice(), 0);
+    assert_eq!((paid.0, claimable.0), (25_000, 25_000));
+}
+
+fn cooldown_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    pool["claim_cooldown_ms"] = json!(QUARTER_DAY);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+    contract
+}
+
+#[test]
+fn claims_outside_cooldown_are_paid() {
+    let mut contract = cooldown_pool();
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+    as_user(&alice(), END_TIME + 2 * QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    assert_eq!(contract.rewards_paid(0).0, reward);
+}
+
+#[test]
+#[should_panic(expected = "claim cooldown active")]
+fn claims_within_cooldown_are_rejected() {
+    let mut contract = cooldown_pool();
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+    as_user(&alice(), END_TIME + 2 * QUARTER_DAY - 1);
+    contract.claim_quarterly_payout(0, 0);
+}
+
+#[test]
+#[should_panic(expected = "invalid position index")]
+fn quarterly_claim_rejects_unknown_index() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 1);
+}
+
+#[test]
+#[should_panic(expected = "not staked")]
+fn quarterly_claim_rejects_loans() {
+    let mut pool = loan_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 100_000, 0);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+}
+
+#[test]
+fn many_small_claims_pay_the_same_as_one_large_claim() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 987_654, START_TIME);
+    stake(&mut contract, &bob(), 0, 987_654, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    let mut claim_time = END_TIME + QUARTER_DAY;
+    while claim_time < END_TIME + ONE_YEAR {
+        as_user(&alice(), claim_time);
+        contract.claim_quarterly_payout(0, 0);
+        claim_time += 86_400_000 + 1_234;
+    }
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.claim_quarterly_payout(0, 0);
+    as_user(&bob(), END_TIME + ONE_YEAR);
+    contract.claim_quarterly_payout(0, 0);
+
+    let alice_paid = as_u128(&position_json(&contract, 0, &alice(), 0)["paid_out"]);
+    let bob_paid = as_u128(&position_json(&contract, 0, &bob(), 0)["paid_out"]);
+    assert_eq!(alice_paid, bob_paid);
+    // 10% of the stake, give or take the accumulator rounding
+    assert!(98_765 - alice_paid <= 1);
+}
+
+#[test]
+fn apy_change_applies_from_the_edit_on() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    let mut pool = staking_pool_json();
+    pool["apy"] = json!(20);
+    as_owner(END_TIME + ONE_YEAR / 2);
+    contract.edit_pool(0, pool_from_json(pool));
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), 50_000 + 100_000);
+}
+
+#[test]
+fn boost_only_applies_within_its_window() {
+    let mut boosted = staking_pool_json();
+    // +50% during the second half of the reward year
+    boosted["boost"] = json!([END_TIME + ONE_YEAR / 2, END_TIME + 2 * ONE_YEAR, 5_000]);
+    let mut contract = contract_with_pool(boosted);
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    let mut plain = contract_with_pool(staking_pool_json());
+    stake(&mut plain, &alice(), 0, 1_000_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), 50_000);
+    assert_eq!(plain.calculate_interest(alice(), 0, 0, 1_000_000), 50_000);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 1_000_000), 50_000 + 75_000);
+    assert_eq!(plain.calculate_interest(alice(), 0, 0, 1_000_000), 100_000);
+}
+
+#[test]
+#[should_panic(expected = "Pool Paused")]
+fn claims_are_rejected_while_pool_paused() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    as_owner(END_TIME);
+    contract.set_pool_paused(0, true);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+}
+
+#[test]
+#[should_panic(expected = "Rewards Paused")]
+fn claims_are_rejected_while_rewards_paused() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    as_owner(END_TIME);
+    contract.set_rewards_paused(0, true);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    contract.claim_quarterly_payout(0, 0);
+}
+
+#[test]
+fn claim_all_rewards_pays_every_matured_position() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    stake(&mut contract, &alice(), 0, 300_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY - 1);
+    assert_eq!(contract.claim_all_rewards(0).0, 0);
+    assert_eq!(contract.rewards_paid(0).0, 0);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(contract.claim_all_rewards(0).0, 10_000 + 30_000);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 1)["paid_out"]), 30_000);
+}
+
+#[test]
+fn claim_all_rewards_skips_loans() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 500_000, 0);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), ONE_YEAR);
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    assert!(reward > 0);
+    assert_eq!(contract.claim_all_rewards(0).0, reward);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 1)["paid_out"]), 0);
+}
+
+#[test]
+fn simulated_rewards_follow_the_apy() {
+    let contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.simulate_rewards(0, 1_000_000, ONE_YEAR / 2).0, 50_000);
+    assert_eq!(contract.simulate_rewards(0, 1_000_000, ONE_YEAR * 3).0, 100_000);
+}
+
+#[test]
+fn simulated_rewards_match_a_real_stake() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    let earned = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    assert_eq!(contract.simulate_rewards(0, 1_000_000, ONE_YEAR).0, earned);
+}
+
+fn reward_calls() -> Vec<(String, String)> {
+    scheduled_calls()
+        .into_iter()
+        .filter(|call| call.0 == token())
+        .map(|call| (call.1, call.2))
+        .collect()
+}
+
+#[test]
+fn claims_transfer_from_the_reserve_by_default() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.claim_quarterly_payout(0, 0);
+
+    let calls = reward_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0, "ft_transfer");
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 1_000_000 - reward);
+}
+
+#[test]
+fn claims_mint_rewards_when_configured() {
+    let mut pool = staking_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    pool["mint_rewards"] = json!(true);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    let reward = contract.calculate_interest(alice(), 0, 0, 1_000_000);
+    contract.claim_quarterly_payout(0, 0);
+
+    let calls = reward_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0, "ft_mint");
+    assert!(calls[0].1.contains(&format!("\"amount\":{}", reward)));
+    assert!(events("RewardShortfall").is_empty());
+    assert_eq!(contract.rewards_paid(0).0, reward);
+    assert_eq!(as_u128(&pool_json(&contract, 0)["funds"]["reward_reserve"]), 0);
+}
+
+#[test]
+fn withdraw_mints_the_reward_beside_the_principal() {
+    let mut pool = staking_pool_json();
+    pool["mint_rewards"] = json!(true);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 10_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 10_000);
+
+    let calls = reward_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].0, "ft_mint");
+    assert!(calls[0].1.contains("\"amount\":1000"));
+    assert_eq!(calls[1].0, "ft_transfer");
+    assert!(calls[1].1.contains("\"amount\":\"10000\""));
+}
+
+#[test]
+fn realized_apy_with_constant_tvl() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+    assert_eq!(contract.realized_apy(0).0, 0);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.claim_quarterly_payout(0, 0);
+    // a year of 10% paid on the stake, held a little longer because of the deposit window
+    assert_eq!(contract.rewards_paid(0).0, 100_000);
+    assert_eq!(contract.realized_apy(0).0, 999);
+}
+
+fn fixed_budget_pool() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["reward_mode"] = json!({ "FixedBudget": { "total_reward": 1_000_000, "period": ONE_YEAR / 2 } });
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 123_457, START_TIME);
+    stake(&mut contract, &bob(), 0, 654_321, START_TIME);
+    stake(&mut contract, &carol(), 0, 111_111, START_TIME);
+    contract
+}
+
+fn total_earned(contract: &staking_pool::Contract) -> u128 {
+    contract.calculate_interest(alice(), 0, 0, 123_457)
+        + contract.calculate_interest(bob(), 0, 0, 654_321)
+        + contract.calculate_interest(carol(), 0, 0, 111_111)
+}
+
+#[test]
+fn fixed_budget_is_released_over_the_period() {
+    let contract = fixed_budget_pool();
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 4);
+    let halfway = total_earned(&contract);
+    assert!(halfway <= 500_000 && halfway >= 499_997);
+    // stake share of 123_457 out of 888_889
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 123_457), 69_444);
+    assert_eq!(contract.simulate_rewards(0, 111_111, ONE_YEAR / 4).0, 55_555);
+}
+
+#[test]
+fn fixed_budget_is_never_exceeded() {
+    let contract = fixed_budget_pool();
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    let at_period_end = total_earned(&contract);
+    assert!(at_period_end <= 1_000_000 && at_period_end >= 999_997);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(total_earned(&contract), at_period_end);
+}
+
+fn rewards_at_decimals(reward_decimals: Option<u8>) -> (u128, u128) {
+    let mut contract = contract_with_pool(staking_pool_json());
+    if let Some(decimals) = reward_decimals {
+        as_owner(0);
+        contract.set_reward_decimals(0, decimals);
+    }
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    (contract.calculate_interest(alice(), 0, 0, 1_000_000), contract.simulate_rewards(0, 1_000_000, ONE_YEAR).0)
+}
+
+#[test]
+fn reward_decimals_leave_payouts_unchanged() {
+    // rewards are paid in the staked token, the override only changes how they are displayed
+    assert_eq!(rewards_at_decimals(None), (100_000, 100_000));
+    assert_eq!(rewards_at_decimals(Some(18)), (100_000, 100_000));
+    assert_eq!(rewards_at_decimals(Some(30)), (100_000, 100_000));
+}
+
+/// Budget and stakes at 24 decimals, amounts past u64 have to go through the JSON text
+fn large_fixed_budget_pool(total_reward: u128) -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["reward_mode"] = json!({ "FixedBudget": { "total_reward": "TOTAL_REWARD", "period": ONE_YEAR / 2 } });
+    pool["deposit_limiters"]["limit_per_user"] = json!("LIMIT");
+    pool["deposit_limiters"]["capacity"] = json!("LIMIT");
+    let text = pool.to_string()
+        .replace("\"TOTAL_REWARD\"", &total_reward.to_string())
+        .replace("\"LIMIT\"", &u128::MAX.to_string());
+
+    let mut contract = new_contract();
+    contract.create_pool(serde_json::from_str(&text).unwrap(), PoolType::Staking);
+    contract
+}
+
+#[test]
+fn fixed_budget_with_24_decimal_amounts() {
+    let one_token = 10u128.pow(24);
+    let mut contract = large_fixed_budget_pool(1_000_000 * one_token);
+    assert_eq!(events("RewardReserveUnfunded").len(), 1);
+    stake(&mut contract, &alice(), 0, 500 * one_token, START_TIME);
+    stake(&mut contract, &bob(), 0, 1_500 * one_token, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000 * one_token);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 4);
+    // half the budget released, a quarter of it to alice
+    assert_eq!(contract.calculate_interest(alice(), 0, 0, 500 * one_token), 125_000 * one_token);
+    assert_eq!(contract.calculate_interest(bob(), 0, 0, 1_500 * one_token), 375_000 * one_token);
+    // the reserve holds the whole budget, enough for a full period of release
+    assert_eq!(contract.solvency(0).0, 100);
+    assert_eq!(contract.reserve_runway(0), ONE_YEAR / 2);
+
+    as_user(&alice(), END_TIME + ONE_YEAR / 2);
+    assert_eq!(contract.reserve_runway(0), u64::MAX);
+}
+
+#[test]
+#[should_panic(expected = "fixed budget needs a staking pool and a period")]
+fn fixed_budget_loan_pool_is_rejected() {
+    let mut pool = loan_pool_json();
+    pool["reward_mode"] = json!({ "FixedBudget": { "total_reward": 1_000_000, "period": ONE_YEAR } });
+    contract_with_pool(pool);
+}
+
+#[test]
+fn claimable_quarters_count_boundaries_since_last_claim() {
+    let mut contract = quarterly_pool();
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    stake(&mut contract, &alice(), 0, 500_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + QUARTER_DAY - 1);
+    assert!(contract.claimable_quarters(0, alice()).is_empty());
+    as_user(&alice(), END_TIME + QUARTER_DAY);
+    assert_eq!(contract.claimable_quarters(0, alice()), vec![(0, 1), (1, 1)]);
+    as_user(&alice(), END_TIME + 2 * QUARTER_DAY + 5);
+    assert_eq!(contract.claimable_quarters(0, alice()), vec![(0, 2), (1, 2)]);
+
+    // the claim only paid position 0, position 1 keeps counting from the start
+    contract.claim_quarterly_payout(0, 0);
+    assert_eq!(contract.claimable_quarters(0, alice()), vec![(1, 2)]);
+    as_user(&alice(), END_TIME + 3 * QUARTER_DAY);
+    assert_eq!(contract.claimable_quarters(0, alice()), vec![(0, 1), (1, 3)]);
+
+    // quarters stop counting at the end of the one year duration
+    as_user(&alice(), END_TIME + 10 * ONE_YEAR);
+    assert_eq!(contract.claimable_quarters(0, alice()), vec![(0, 2), (1, 4)]);
+}
+
+#[test]
+fn claimable_quarters_empty_for_regular_pools() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+
+    as_user(&alice(), END_TIME + 2 * QUARTER_DAY);
+    assert!(contract.claimable_quarters(0, alice()).is_empty());
+}
+
+fn fee_token() -> near_sdk::AccountId {
+    "fee.near".parse().unwrap()
+}
+
+fn pool_with_claim_burn() -> staking_pool::Contract {
+    let mut pool = staking_pool_json();
+    pool["claim_burn"] = json!([fee_token(), 500]);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    stake(&mut contract, &alice(), 0, 300_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+    contract
+}
+
+#[test]
+fn claim_burns_the_configured_amount_before_paying() {
+    let mut contract = pool_with_claim_burn();
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    assert_eq!(contract.claim_all_rewards(0).0, 0);
+
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, fee_token());
+    assert_eq!(calls[0].1, "ft_burn");
+    assert!(calls[0].2.contains("\"account_id\":\"alice.near\""));
+    assert!(calls[0].2.contains("\"amount\":500"));
+    assert_eq!(calls[1].1, "resolve_claim_burn");
+    assert_eq!(contract.rewards_paid(0).0, 0);
+
+    as_owner(END_TIME + ONE_YEAR);
+    assert_eq!(contract.resolve_claim_burn(alice(), 0, None, 0, Ok(())).0, 10_000 + 30_000);
+    assert_eq!(as_u128(&position_json(&contract, 0, &alice(), 1)["paid_out"]), 30_000);
+}
+
+#[test]
+fn quarterly_claim_with_burn_pays_one_position() {
+    let mut pool = staking_pool_json();
+    pool["quarterly_payout"] = json!(true);
+    pool["claim_burn"] = json!([fee_token(), 500]);
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1_000_000);
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.claim_quarterly_payout(0, 0);
+    assert!(scheduled_calls().iter().any(|call| call.0 == fee_token() && call.1 == "ft_burn"));
+
+    as_owner(END_TIME + ONE_YEAR);
+    assert_eq!(contract.resolve_claim_burn(alice(), 0, Some(0), ONE_YEAR, Ok(())).0, 10_000);
+}
+
+#[test]
+#[should_panic(expected = "claim burn failed")]
+fn claim_is_rejected_without_the_burn_token() {
+    let mut contract = pool_with_claim_burn();
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.claim_all_rewards(0);
+
+    as_owner(END_TIME + ONE_YEAR);
+    contract.resolve_claim_burn(alice(), 0, None, 0, Err(near_sdk::PromiseError::Failed));
+}
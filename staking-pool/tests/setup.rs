@@ -0,0 +1,242 @@
+#![allow(dead_code)]
+extern crate staking_pool;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::mock::VmAction;
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{testing_env, AccountId, PromiseOrValue};
+use staking_pool::{Contract, PoolInfo, PoolType};
+
+pub const START_TIME: u64 = 1_000;
+pub const END_TIME: u64 = 10_000;
+pub const ONE_YEAR: u64 = 86400_000 * 365;
+
+pub fn pool_account() -> AccountId {
+    "pool.near".parse().unwrap()
+}
+
+pub fn token() -> AccountId {
+    "token.near".parse().unwrap()
+}
+
+pub fn collateral() -> AccountId {
+    "collateral.near".parse().unwrap()
+}
+
+pub fn alice() -> AccountId {
+    "alice.near".parse().unwrap()
+}
+
+pub fn bob() -> AccountId {
+    "bob.near".parse().unwrap()
+}
+
+pub fn carol() -> AccountId {
+    "carol.near".parse().unwrap()
+}
+
+/// Sets up a context where `signer` signed the transaction and `predecessor` made the call.
+pub fn set_context(signer: &AccountId, predecessor: &AccountId, timestamp_ms: u64) {
+    let context = VMContextBuilder::new()
+        .current_account_id(pool_account())
+        .signer_account_id(signer.clone())
+        .predecessor_account_id(predecessor.clone())
+        .block_timestamp(timestamp_ms * 1_000_000)
+        .attached_deposit(1)
+        .build();
+    testing_env!(context);
+}
+
+pub fn as_user(user: &AccountId, timestamp_ms: u64) {
+    set_context(user, user, timestamp_ms);
+}
+
+pub fn as_owner(timestamp_ms: u64) {
+    as_user(&pool_account(), timestamp_ms);
+}
+
+pub fn staking_pool_json() -> Value {
+    // nested objects are built apart to keep `json!` under the macro recursion limit
+    let token_info = json!({
+        "token": token(),
+        "collateral_token": collateral(),
+        "decimals": 24,
+        "reward_decimals": null,
+        "collateral_decimals": null,
+        "accepted_collateral": [],
+        "name": "Token",
+        "symbol": "TKN",
+    });
+    let funds = json!({
+        "balance": 0,
+        "loaned_balance": 0,
+        "reward_reserve": 0,
+        "total_rewards_paid": 0,
+        "fees_collected": 0,
+        "tvl_time": 0,
+    });
+    let deposit_limiters = json!({
+        "duration": ONE_YEAR,
+        "start_time": START_TIME,
+        "end_time": END_TIME,
+        "limit_per_user": 1000000,
+        "capacity": 10000000,
+        "max_utilisation": 80,
+        "min_reserve_bps": 0,
+        "withdraw_buffer_bps": 0,
+        "stake_cooldown_ms": 0,
+    });
+
+    json!({
+        "pool_name": "staking",
+        "icon": null,
+        "description": null,
+        "pool_type": "Staking",
+        "apy": 10,
+        "reward_mode": "Apy",
+        "boost": null,
+        "min_interest": 0,
+        "max_interest_bps": 0,
+        "withdraw_fee_bps": 0,
+        "origination_fee_bps": 0,
+        "interest_model": "Linear",
+        "claim_cooldown_ms": 0,
+        "claim_burn": null,
+        "collateral_burn": true,
+        "collateral_sink": null,
+        "outflow_limit": 0,
+        "outflow_window_ms": 0,
+        "outflow_window_start": 0,
+        "outflow_volume": 0,
+        "paused": false,
+        "deposits_closed": false,
+        "gated": false,
+        "rewards_paused": false,
+        "rewards_paused_at": 0,
+        "rewards_paused_duration": 0,
+        "reward_per_token_stored": 0,
+        "reward_updated_at": 0,
+        "quarterly_payout": false,
+        "mint_rewards": false,
+        "unique_users": 0,
+        "active_borrowers": 0,
+        "token_info": token_info,
+        "funds": funds,
+        "deposit_limiters": deposit_limiters,
+        "archived": false,
+    })
+}
+
+pub fn loan_pool_json() -> Value {
+    let mut pool = staking_pool_json();
+    pool["pool_name"] = json!("loan");
+    pool["pool_type"] = json!("Loan");
+    pool
+}
+
+pub fn pool_from_json(value: Value) -> PoolInfo {
+    serde_json::from_value(value).unwrap()
+}
+
+pub fn new_contract() -> Contract {
+    as_owner(0);
+    Contract::new()
+}
+
+/// Creates a contract with a single pool built from `value`.
+pub fn contract_with_pool(value: Value) -> Contract {
+    let mut contract = new_contract();
+    let pool_type = serde_json::from_value::<PoolType>(value["pool_type"].clone()).unwrap();
+    contract.create_pool(pool_from_json(value), pool_type);
+    contract
+}
+
+/// Simulates `token` calling `ft_on_transfer` on behalf of `sender`.
+pub fn transfer_call(
+    contract: &mut Contract,
+    token: &AccountId,
+    sender: &AccountId,
+    amount: u128,
+    msg: &str,
+    timestamp_ms: u64,
+) -> u128 {
+    set_context(sender, token, timestamp_ms);
+    match contract.ft_on_transfer(sender.clone(), U128(amount), msg.to_string()) {
+        PromiseOrValue::Value(unused) => unused.0,
+        PromiseOrValue::Promise(_) => panic!("unexpected promise"),
+    }
+}
+
+pub fn stake(contract: &mut Contract, user: &AccountId, pid: u128, amount: u128, timestamp_ms: u64) {
+    transfer_call(contract, &token(), user, amount, &format!("staking:{}", pid), timestamp_ms);
+}
+
+pub fn fund_rewards(contract: &mut Contract, pid: u128, amount: u128) {
+    transfer_call(contract, &token(), &pool_account(), amount, &format!("reward:{}", pid), 0);
+}
+
+pub fn pool_json(contract: &Contract, pid: u128) -> Value {
+    let pools = contract.get_pool_info(pid, pid + 1);
+    serde_json::to_value(&pools[0]).unwrap()
+}
+
+pub fn position_json(contract: &Contract, pid: u128, user: &AccountId, index: u128) -> Value {
+    let positions = contract.get_user_stakes(pid, user.clone(), index, index + 1);
+    serde_json::to_value(&positions[0]).unwrap()
+}
+
+pub fn as_u128(value: &Value) -> u128 {
+    match value {
+        Value::String(s) => s.parse().unwrap(),
+        other => other.as_u64().map(u128::from).unwrap(),
+    }
+}
+
+/// Returns the `data` payloads of logged events with the given name.
+pub fn events(name: &str) -> Vec<Value> {
+    near_sdk::test_utils::get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|log| serde_json::from_str::<Value>(log).unwrap())
+        .filter(|event| event["event"] == name)
+        .map(|event| event["data"][0].clone())
+        .collect()
+}
+
+/// Returns `(receiver, method, args)` of every function call scheduled so far.
+pub fn scheduled_calls() -> Vec<(AccountId, String, String)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver = receipt.receiver_id.clone();
+            receipt.actions.into_iter().filter_map(move |action| match action {
+                VmAction::FunctionCall { function_name, args, .. } => {
+                    Some((receiver.clone(), function_name, String::from_utf8(args).unwrap()))
+                }
+                _ => None,
+            })
+        })
+        .collect()
+}
+
+pub fn borrow(contract: &mut Contract, user: &AccountId, pid: u128, amount: u128, timestamp_ms: u64) {
+    as_owner(timestamp_ms);
+    contract.whitelist(pid, user.clone(), true);
+    as_user(user, timestamp_ms);
+    contract.borrow(pid, amount);
+}
+
+pub fn repay(
+    contract: &mut Contract,
+    user: &AccountId,
+    pid: u128,
+    index: usize,
+    amount: u128,
+    repay_amount: u128,
+    timestamp_ms: u64,
+) -> u128 {
+    let msg = format!("borrow:{}:{}:{}", pid, index, repay_amount);
+    transfer_call(contract, &token(), user, amount, &msg, timestamp_ms)
+}
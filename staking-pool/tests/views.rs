@@ -0,0 +1,443 @@
+mod setup;
+
+use near_sdk::json_types::U128;
+use near_contract_standards::fungible_token::metadata::{FungibleTokenMetadata, FT_METADATA_SPEC};
+use setup::*;
+
+#[test]
+fn solvency_of_fully_reserved_pool() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    // 10% apy over one year
+    fund_rewards(&mut contract, 0, 100_000);
+
+    assert_eq!(contract.solvency(0).0, 100);
+}
+
+#[test]
+fn solvency_of_under_reserved_pool() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 45_000);
+
+    assert_eq!(contract.solvency(0).0, 95);
+}
+
+#[test]
+fn solvency_of_empty_pool() {
+    let contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.solvency(0).0, u128::MAX);
+}
+
+#[test]
+fn average_stake_of_empty_pool_is_zero() {
+    let contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.average_stake(0).0, 0);
+}
+
+#[test]
+fn average_stake_divides_balance_by_users() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &alice(), 0, 200, START_TIME);
+    stake(&mut contract, &bob(), 0, 600, START_TIME);
+
+    assert_eq!(contract.average_stake(0).0, 450);
+}
+
+#[test]
+fn pools_near_capacity_filters_by_fill_level() {
+    let mut contract = new_contract();
+    for capacity in [1_000, 1_000, 1_000, 0] {
+        let mut pool = staking_pool_json();
+        pool["deposit_limiters"]["capacity"] = capacity.into();
+        as_owner(0);
+        contract.create_pool(pool_from_json(pool), staking_pool::PoolType::Staking);
+    }
+    stake(&mut contract, &alice(), 0, 500, START_TIME);
+    stake(&mut contract, &alice(), 1, 900, START_TIME);
+    stake(&mut contract, &alice(), 2, 1_000, START_TIME);
+
+    assert_eq!(contract.pools_near_capacity(9_000), vec![1, 2]);
+    assert_eq!(contract.pools_near_capacity(5_000), vec![0, 1, 2]);
+    assert_eq!(contract.pools_near_capacity(0), vec![0, 1, 2]);
+}
+
+#[test]
+fn debug_user_state_reports_staker_bookkeeping() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &alice(), 0, 250, START_TIME);
+
+    // a view call, so no owner signature is needed
+    as_user(&bob(), START_TIME);
+    let state = contract.debug_user_state(0, alice());
+    assert!(state.is_pool_user);
+    assert!(!state.is_whitelisted);
+    assert_eq!(state.total_staked.0, 350);
+    assert_eq!(state.total_borrowed.0, 0);
+    assert_eq!(state.positions, 2);
+
+    let unknown = contract.debug_user_state(0, bob());
+    assert!(!unknown.is_pool_user);
+    assert_eq!(unknown.positions, 0);
+}
+
+#[test]
+fn staked_balances_in_input_order() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 100, START_TIME);
+    stake(&mut contract, &bob(), 0, 300, START_TIME);
+
+    let unknown: near_sdk::AccountId = "carol.near".parse().unwrap();
+    let balances: Vec<u128> = contract
+        .staked_balances(0, vec![bob(), unknown, alice()])
+        .into_iter()
+        .map(|balance| balance.0)
+        .collect();
+    assert_eq!(balances, vec![300, 0, 100]);
+}
+
+#[test]
+#[should_panic(expected = "too many users requested")]
+fn staked_balances_caps_input() {
+    let contract = contract_with_pool(staking_pool_json());
+    contract.staked_balances(0, vec![alice(); staking_pool::MAX_BATCH_QUERY + 1]);
+}
+
+#[test]
+fn claimable_positions_wait_for_unlock() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &alice(), 0, 2_000, START_TIME);
+
+    as_user(&alice(), END_TIME + ONE_YEAR - 1);
+    assert!(contract.claimable_positions(0, alice()).is_empty());
+
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    let positions: Vec<(usize, u128, u128)> = contract.claimable_positions(0, alice())
+        .into_iter()
+        .map(|(index, principal, reward)| (index, principal.0, reward.0))
+        .collect();
+    assert_eq!(positions, vec![(0, 1_000, 100), (1, 2_000, 200)]);
+}
+
+#[test]
+fn claimable_positions_skip_loans() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, 0);
+    borrow(&mut contract, &alice(), 0, 100_000, 0);
+    stake(&mut contract, &alice(), 0, 1_000, 0);
+
+    as_user(&alice(), END_TIME);
+    let indexes: Vec<usize> = contract.claimable_positions(0, alice()).into_iter().map(|position| position.0).collect();
+    assert_eq!(indexes, vec![0, 2]);
+    assert!(contract.claimable_positions(0, bob()).is_empty());
+}
+
+#[test]
+fn reserve_runway_of_funded_pool() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    // two years of 10% apy
+    fund_rewards(&mut contract, 0, 200_000);
+
+    assert_eq!(contract.reserve_runway(0), 2 * ONE_YEAR);
+}
+
+#[test]
+fn reserve_runway_of_nearly_empty_reserve() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 1_000_000, START_TIME);
+    fund_rewards(&mut contract, 0, 1);
+
+    // 100_000 a year is about 315 seconds per token
+    assert_eq!(contract.reserve_runway(0), ONE_YEAR / 100_000);
+}
+
+#[test]
+fn reserve_runway_without_stake() {
+    let contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.reserve_runway(0), u64::MAX);
+}
+
+#[test]
+fn deposit_window_tracks_block_time() {
+    let contract = contract_with_pool(staking_pool_json());
+
+    as_user(&alice(), START_TIME - 1);
+    assert_eq!(contract.deposit_window(0), (START_TIME, END_TIME, false));
+    as_user(&alice(), START_TIME);
+    assert_eq!(contract.deposit_window(0), (START_TIME, END_TIME, true));
+    as_user(&alice(), END_TIME);
+    assert_eq!(contract.deposit_window(0), (START_TIME, END_TIME, true));
+    as_user(&alice(), END_TIME + 1);
+    assert_eq!(contract.deposit_window(0), (START_TIME, END_TIME, false));
+}
+
+#[test]
+fn global_solvency_snapshot_per_token() {
+    let other: near_sdk::AccountId = "other.near".parse().unwrap();
+    let mut contract = contract_with_pool(staking_pool_json());
+    let mut other_pool = loan_pool_json();
+    other_pool["token_info"]["token"] = other.to_string().into();
+    contract.create_pool(pool_from_json(other_pool), staking_pool::PoolType::Loan);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &alice(), 2, 500, START_TIME);
+    fund_rewards(&mut contract, 0, 100);
+    transfer_call(&mut contract, &other, &bob(), 2_000, "staking:1", 0);
+    borrow(&mut contract, &alice(), 1, 400, 0);
+
+    as_owner(0);
+    contract.refresh_global_solvency();
+    let queried: Vec<near_sdk::AccountId> = scheduled_calls()
+        .into_iter()
+        .filter(|call| call.1 == "ft_balance_of")
+        .map(|call| call.0)
+        .collect();
+    assert_eq!(queried, vec![other.clone(), token()]);
+
+    contract.global_solvency_callback(token(), U128(1_700));
+    contract.global_solvency_callback(other.clone(), U128(1_500));
+    let snapshot: Vec<(near_sdk::AccountId, u128, u128)> = contract.global_solvency()
+        .into_iter()
+        .map(|(token, held, owed)| (token, held.0, owed.0))
+        .collect();
+    assert_eq!(snapshot, vec![(other, 1_500, 1_600), (token(), 1_700, 1_600)]);
+}
+
+#[test]
+fn pools_by_ids_skips_unknown_pids() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+
+    let pools = contract.pools_by_ids(vec![1, 7, 0, 1]);
+    let names: Vec<near_sdk::serde_json::Value> = pools
+        .iter()
+        .map(|pool| near_sdk::serde_json::to_value(pool).unwrap()["pool_name"].clone())
+        .collect();
+    assert_eq!(names, vec!["loan", "staking", "loan"]);
+    assert!(scheduled_calls().iter().all(|call| call.1 != "ft_metadata"));
+}
+
+#[test]
+fn top_positions_are_largest_first() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    stake(&mut contract, &alice(), 0, 300, START_TIME);
+    stake(&mut contract, &bob(), 0, 900, START_TIME);
+    stake(&mut contract, &alice(), 0, 500, START_TIME);
+    stake(&mut contract, &carol(), 0, 100, START_TIME);
+    stake(&mut contract, &carol(), 0, 500, START_TIME);
+
+    let top: Vec<(near_sdk::AccountId, usize, u128)> = contract.top_positions(0, 4)
+        .into_iter()
+        .map(|(user, index, amount)| (user, index, amount.0))
+        .collect();
+    assert_eq!(top, vec![(bob(), 0, 900), (alice(), 1, 500), (carol(), 1, 500), (alice(), 0, 300)]);
+    assert!(contract.top_positions(7, 4).is_empty());
+}
+
+#[test]
+fn healthy_pool_has_no_violations() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000, 0);
+    stake(&mut contract, &bob(), 0, 500, 0);
+    borrow(&mut contract, &alice(), 0, 400, 0);
+
+    assert!(contract.check_invariants(0).is_empty());
+}
+
+#[test]
+fn corrupted_pool_reports_violations() {
+    let mut contract = contract_with_pool(loan_pool_json());
+    stake(&mut contract, &bob(), 0, 1_000, 0);
+    borrow(&mut contract, &alice(), 0, 400, 0);
+
+    contract.set_funds_for_testing(0, 300, 400);
+    contract.set_unique_users_for_testing(0, 5);
+    assert_eq!(
+        contract.check_invariants(0),
+        vec![
+            "staked positions 1000 != balance 300",
+            "loaned_balance 400 > balance 300",
+            "unique_users 5 != pool users 2",
+        ]
+    );
+}
+
+#[test]
+fn refreshed_token_metadata_is_stored() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_user(&alice(), 0);
+    contract.refresh_token_metadata(0);
+
+    let calls = scheduled_calls();
+    assert_eq!(calls[0].0, token());
+    assert_eq!(calls[0].1, "ft_metadata");
+    assert_eq!(calls[1].1, "ft_metadata_callback");
+
+    as_user(&pool_account(), 0);
+    let meta = FungibleTokenMetadata {
+        spec: FT_METADATA_SPEC.to_string(),
+        name: "Wrapped Near".to_string(),
+        symbol: "wNEAR".to_string(),
+        icon: None,
+        reference: None,
+        reference_hash: None,
+        decimals: 18,
+    };
+    contract.ft_metadata_callback(0, meta);
+
+    let token_info = &pool_json(&contract, 0)["token_info"];
+    assert_eq!(token_info["name"], "Wrapped Near");
+    assert_eq!(token_info["symbol"], "wNEAR");
+    assert_eq!(token_info["decimals"], 18);
+}
+
+#[test]
+fn zero_decimals_in_metadata_keep_stored_decimals() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    as_user(&pool_account(), 0);
+    let meta = FungibleTokenMetadata {
+        spec: FT_METADATA_SPEC.to_string(),
+        name: "Broken".to_string(),
+        symbol: "BRK".to_string(),
+        icon: None,
+        reference: None,
+        reference_hash: None,
+        decimals: 0,
+    };
+    contract.ft_metadata_callback(0, meta);
+
+    let token_info = &pool_json(&contract, 0)["token_info"];
+    assert_eq!(token_info["decimals"], 24);
+    assert_eq!(token_info["symbol"], "BRK");
+    let warnings = events("ImplausibleDecimals");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["reported"], 0);
+    assert_eq!(warnings[0]["kept"], 24);
+}
+
+#[test]
+fn token_display_matches_cached_metadata() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.token_display(0), ("Token".to_string(), "TKN".to_string(), 24));
+
+    as_user(&pool_account(), 0);
+    let meta = FungibleTokenMetadata {
+        spec: FT_METADATA_SPEC.to_string(),
+        name: "Wrapped Near".to_string(),
+        symbol: "wNEAR".to_string(),
+        icon: None,
+        reference: None,
+        reference_hash: None,
+        decimals: 18,
+    };
+    contract.ft_metadata_callback(0, meta);
+    assert_eq!(contract.token_display(0), ("Wrapped Near".to_string(), "wNEAR".to_string(), 18));
+}
+
+#[test]
+fn reward_tokens_are_distinct() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    let other: near_sdk::AccountId = "other.near".parse().unwrap();
+    let mut other_pool = loan_pool_json();
+    other_pool["token_info"]["token"] = other.to_string().into();
+    contract.create_pool(pool_from_json(other_pool), staking_pool::PoolType::Loan);
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+
+    assert_eq!(contract.reward_tokens(), vec![other, token()]);
+}
+
+#[test]
+fn pools_using_token_match_every_role() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    let other: near_sdk::AccountId = "other.near".parse().unwrap();
+    let mut staked_other = loan_pool_json();
+    staked_other["token_info"]["token"] = other.to_string().into();
+    staked_other["token_info"]["collateral_token"] = token().to_string().into();
+    contract.create_pool(pool_from_json(staked_other), staking_pool::PoolType::Loan);
+    let mut accepts_other = loan_pool_json();
+    accepts_other["token_info"]["accepted_collateral"] = near_sdk::serde_json::json!([[other, 5_000]]);
+    contract.create_pool(pool_from_json(accepts_other), staking_pool::PoolType::Loan);
+
+    assert_eq!(contract.pools_using_token(token()), vec![0, 1, 2]);
+    assert_eq!(contract.pools_using_token(other), vec![1, 2]);
+    assert_eq!(contract.pools_using_token(collateral()), vec![0, 2]);
+    assert!(contract.pools_using_token(bob()).is_empty());
+}
+
+#[test]
+fn accrued_fees_follow_withdrawals_and_loans() {
+    let mut staking = staking_pool_json();
+    staking["withdraw_fee_bps"] = 100.into();
+    let mut contract = contract_with_pool(staking);
+    let other: near_sdk::AccountId = "other.near".parse().unwrap();
+    let mut loan = loan_pool_json();
+    loan["token_info"]["token"] = other.to_string().into();
+    loan["origination_fee_bps"] = 200.into();
+    contract.create_pool(pool_from_json(loan), staking_pool::PoolType::Loan);
+    contract.create_pool(pool_from_json(staking_pool_json()), staking_pool::PoolType::Staking);
+
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    as_user(&alice(), END_TIME + ONE_YEAR);
+    contract.withdraw(0, 0, 40_000);
+    contract.withdraw(0, 0, 60_000);
+    transfer_call(&mut contract, &other, &bob(), 1_000_000, "staking:1", 0);
+    borrow(&mut contract, &alice(), 1, 100_000, 0);
+    borrow(&mut contract, &carol(), 1, 50_000, 0);
+
+    assert_eq!(contract.accrued_fees(0), U128(1_000));
+    assert_eq!(contract.accrued_fees(1), U128(3_000));
+    assert_eq!(contract.accrued_fees(2), U128(0));
+    let totals = contract.total_accrued_fees();
+    assert_eq!(totals.len(), 2);
+    assert_eq!(totals[&token()], U128(1_000));
+    assert_eq!(totals[&other], U128(3_000));
+}
+
+#[test]
+fn liabilities_are_grouped_by_token() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    contract.create_pool(pool_from_json(loan_pool_json()), staking_pool::PoolType::Loan);
+    let other: near_sdk::AccountId = "other.near".parse().unwrap();
+    let mut other_pool = loan_pool_json();
+    other_pool["token_info"]["token"] = other.to_string().into();
+    contract.create_pool(pool_from_json(other_pool), staking_pool::PoolType::Loan);
+
+    stake(&mut contract, &alice(), 0, 1_000, START_TIME);
+    stake(&mut contract, &bob(), 1, 2_000, 0);
+    borrow(&mut contract, &alice(), 1, 500, 0);
+    transfer_call(&mut contract, &other, &bob(), 700, "staking:2", 0);
+
+    let liabilities = contract.liabilities_by_token();
+    assert_eq!(liabilities.len(), 2);
+    assert_eq!(liabilities[&token()], U128(3_000));
+    assert_eq!(liabilities[&other], U128(700));
+}
+
+#[test]
+fn available_to_deposit_near_the_user_limit() {
+    let mut contract = contract_with_pool(staking_pool_json());
+    assert_eq!(contract.available_to_deposit(0, alice()), U128(1_000_000));
+
+    stake(&mut contract, &alice(), 0, 900_000, START_TIME);
+    assert_eq!(contract.available_to_deposit(0, alice()), U128(100_000));
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    assert_eq!(contract.available_to_deposit(0, alice()), U128(0));
+}
+
+#[test]
+fn available_to_deposit_near_capacity() {
+    let mut pool = staking_pool_json();
+    pool["deposit_limiters"]["capacity"] = 1_500_000.into();
+    let mut contract = contract_with_pool(pool);
+    stake(&mut contract, &bob(), 0, 1_000_000, START_TIME);
+    stake(&mut contract, &carol(), 0, 400_000, START_TIME);
+
+    assert_eq!(contract.available_to_deposit(0, alice()), U128(100_000));
+    stake(&mut contract, &alice(), 0, 100_000, START_TIME);
+    assert_eq!(contract.available_to_deposit(0, alice()), U128(0));
+}